@@ -4,14 +4,75 @@ export type track_def_t = {
     codec: string
 };
 
+export type track_state_t = "waiting" | "live" | "stalled" | "no_keyframe";
+
+export type track_status_t = {
+    state: track_state_t,
+    since_last_packet_ms?: number,
+    packets_per_sec: number,
+    bytes_per_sec: number,
+    keyframe_interval_ms?: number,
+};
+
+export type data_def_t = {
+    port: number,
+    ip: string,
+    protocol?: "udp" | "tcp",
+    replay_last?: boolean,
+};
+
+export type control_def_t = {
+    udp?: string,
+    url?: string,
+    tokens?: string[],
+    rate_limit?: number,
+    burst?: number,
+};
+
+export type rendition_def_t = track_def_t & {
+    id: string,
+};
+
 export type stream_def_t = {
     id: string,
     default: boolean,
     video?: track_def_t,
-    audio?: track_def_t
+    audio?: track_def_t,
+    data?: data_def_t,
+    control?: control_def_t,
+    priority?: number,
+    renditions?: rendition_def_t[],
+    state: track_state_t,
+    video_status?: track_status_t,
+    audio_status?: track_status_t,
+    rendition_status?: Record<string, track_status_t>,
 }[];
 
+// Messages on the server's "events" data channel
+export type client_event_t =
+    | { type: "fast_start_done", stream_id: string }
+    | { type: "stream_state", stream_id: string, track: string, state: track_state_t }
+    | { type: "stream_paused", stream_id: string, reason: string }
+    | { type: "stream_resumed", stream_id: string }
+    | { type: "rendition_changed", stream_id: string, rendition: string }
+    | { type: "stream_removed", stream_id: string }
+    | { type: "control_rejected", stream_id: string, reason: string }
+    | { type: "streams_assigned", stream_ids: string[] }
+    | { type: "kicked" }
+    | { type: "shutdown" }
+    | {
+        type: "stats",
+        nack: { nacks: number, requested: number, retransmitted: number, missed: number, requested_per_sec: number },
+        lag: { lag_events: number, lagged_packets: number, dropped_packets: number },
+        bandwidth: { estimate_bps: number, send_bps: number, loss: number, remb_bps?: number },
+    };
+
 export const API_STREAMS = "/api/streams";
+export const API_EVENTS = "/api/events";
+export const API_ICE_SERVERS = "/api/ice_servers";
+export const API_TRACKS = "/api/tracks";
+export const API_RENDITION = "/api/rendition";
+export const API_THUMBNAIL = "/api/thumbnail";
 
 
 export type stats_t = {
@@ -29,3 +90,47 @@ export type stats_t = {
 }
 
 export const API_STATS = "/api/stats";
+
+export type candidate_t = {
+    address: string,
+    candidate_type: string,
+    network_type: string,
+};
+
+export type client_info_t = {
+    uid: string,
+    connection_state: string,
+    ice_state: string,
+    age_s: number,
+    connected_s?: number,
+    remote_addr?: string,
+    candidate_pair?: {
+        local: candidate_t,
+        remote: candidate_t,
+        rtt_ms: number,
+        bytes_sent: number,
+        bytes_received: number,
+    },
+    streams: {
+        stream_id: string,
+        mid?: string,
+        rendition?: string,
+        thumbnail: boolean,
+        paused?: "viewer" | "bandwidth",
+        data_channel: boolean,
+        control_channel: boolean,
+        video?: {
+            ssrc: number,
+            bytes_sent: number,
+            packets_sent: number,
+            nack_count: number,
+            pli_count: number,
+            packets_lost: number,
+            fraction_lost: number,
+            jitter_ms?: number,
+            rtt_ms?: number,
+        },
+    }[],
+};
+
+export const API_CLIENTS = "/api/clients";
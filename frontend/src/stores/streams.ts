@@ -1,8 +1,11 @@
 import { derived, get, readable, writable } from "svelte/store";
-import { API_STREAMS, type stream_def_t } from "./net";
+import { API_ICE_SERVERS, API_RENDITION, API_STREAMS, API_THUMBNAIL, API_TRACKS, type client_event_t, type stream_def_t } from "./net";
 
 const uid = uuidv4();
 
+// Authorizes commands sent to streams' control endpoints. Passed to the page as ?token=
+const control_token = new URLSearchParams(location.search).get("token");
+
 export let selected_stream_ids = writable([]);
 let transceivers = {};
 
@@ -26,20 +29,162 @@ export let stream_defs = readable<null | stream_def_t[]>(null, (set) => {
 
 // ========= WEBRTC ========== //
 
-let pc = new RTCPeerConnection({
-    iceServers: [
-        {
-            urls: 'stun:stun.l.google.com:19302'
+let pc = new RTCPeerConnection();
+
+// Use the same ICE servers (and TURN credentials) as the server.
+// Must be applied before the first offer starts gathering candidates.
+const ice_servers_ready = fetch(API_ICE_SERVERS)
+    .then(r => r.json())
+    .then(iceServers => pc.setConfiguration({ iceServers }))
+    .catch(console.error);
+
+// Restart ICE if the connection drops (ie a Wi-Fi handover) and doesn't recover by itself.
+// The server keeps our session for a grace period while disconnected.
+const ICE_RESTART_DELAY_MS = 2000;
+let ice_restart_timeout = null;
+pc.oniceconnectionstatechange = ev => {
+    console.log(ev);
+    clearTimeout(ice_restart_timeout);
+    if (pc.iceConnectionState != "disconnected") return;
+
+    ice_restart_timeout = setTimeout(() => {
+        if (pc.iceConnectionState == "disconnected" || pc.iceConnectionState == "failed") {
+            console.log("Restarting ICE");
+            signal(get(selected_stream_ids), true);
+        }
+    }, ICE_RESTART_DELAY_MS);
+};
+
+// Trickle ICE: send our candidates to the server as they're gathered...
+function send_candidate(candidate: RTCIceCandidateInit) {
+    fetch(`/api/ice`, {
+        method: "POST",
+        body: JSON.stringify({
+            uid,
+            candidate
+        })
+    });
+}
+
+// The server only takes candidates once it has our first offer, so until then they're held here.
+let pending_candidates: RTCIceCandidateInit[] | null = [];
+pc.onicecandidate = e => {
+    if (!e.candidate) return;
+    if (pending_candidates) pending_candidates.push(e.candidate.toJSON());
+    else send_candidate(e.candidate.toJSON());
+};
+
+// ...and add the server's candidates as they arrive. A null candidate marks the end of gathering,
+// after which the server closes the socket. Empty messages are keep-alives.
+let server_candidates: WebSocket = null;
+function listen_server_candidates() {
+    server_candidates?.close();
+    const scheme = location.protocol == "https:" ? "wss" : "ws";
+    const ws = new WebSocket(`${scheme}://${location.host}/api/ice?uid=${uid}`);
+    server_candidates = ws;
+    ws.onmessage = async e => {
+        if (e.data == "") return;
+        let candidate = JSON.parse(e.data);
+        if (candidate == null) ws.close();
+        try {
+            await pc.addIceCandidate(candidate ?? undefined);
+        } catch (e) {
+            console.error(e);
         }
-    ]
-})
+    };
+}
 
-pc.oniceconnectionstatechange = console.log;
-pc.onicecandidate = e => console.log;
+// Persistent signalling channel. The server sends new offers over it when our
+// streams change on its side (ie a stream is deleted). We're the polite peer:
+// if our offer collides with the server's, ours is dropped in favor of theirs.
+const signalling = pc.createDataChannel("signalling");
+signalling.onmessage = async e => {
+    let desc = JSON.parse(e.data);
+    if (desc.type != "offer") return;
+
+    try {
+        // Implicitly rolls back our own pending offer, if any.
+        await pc.setRemoteDescription(desc);
+        await pc.setLocalDescription(await pc.createAnswer());
+        signalling.send(JSON.stringify(pc.localDescription));
+        update_track_mids();
+    } catch (e) {
+        console.error(e);
+    }
+};
+
+// Events from the server, on the data channel it opens.
+export const server_events = writable<client_event_t | null>(null);
+
+// Latest message of each viewed stream's data track, keyed by stream ID.
+export const telemetry = writable<Record<string, ArrayBuffer>>({});
+
+// Open control channels, and the latest response from each stream's control endpoint, by stream ID.
+const control_channels: Record<string, RTCDataChannel> = {};
+export const control_responses = writable<Record<string, string | ArrayBuffer>>({});
+
+// Sends a command (ie PTZ) to a viewed stream's control endpoint.
+// Rejected commands are reported as control_rejected server events.
+export function send_control(stream_id: string, command: string | ArrayBuffer) {
+    const dc = control_channels[stream_id];
+    if (!dc || dc.readyState != "open") return false;
+    dc.send(command as any);
+    return true;
+}
+
+// The server's events channel. Pause/resume commands are sent back over it.
+let events_channel: RTCDataChannel = null;
+
+// Stops (or restarts) the server sending a viewed stream's video, ie for collapsed tiles.
+// Resuming starts from the stream's latest keyframe, and doesn't renegotiate.
+export function set_paused(stream_id: string, paused: boolean) {
+    if (!events_channel || events_channel.readyState != "open") return false;
+    events_channel.send(JSON.stringify({ type: paused ? "pause" : "resume", stream_id }));
+    return true;
+}
+
+// Hidden tabs don't need video.
+document.addEventListener("visibilitychange", () => {
+    for (const id of get(selected_stream_ids)) {
+        set_paused(id, document.hidden);
+    }
+});
+
+pc.ondatachannel = ev => {
+    const label = ev.channel.label;
+    if (label == "events") {
+        events_channel = ev.channel;
+        ev.channel.onmessage = e => {
+            const event: client_event_t = JSON.parse(e.data);
+            // An operator picked our streams. Adopting them keeps our next signal from undoing it.
+            if (event.type == "streams_assigned") selected_stream_ids.set(event.stream_ids);
+            server_events.set(event);
+        };
+    } else if (label.startsWith("data:")) {
+        const stream_id = label.slice("data:".length);
+        ev.channel.binaryType = "arraybuffer";
+        ev.channel.onmessage = e => telemetry.update(t => ({ ...t, [stream_id]: e.data }));
+        ev.channel.onclose = () => telemetry.update(t => {
+            delete t[stream_id];
+            return t;
+        });
+    } else if (label.startsWith("control:")) {
+        const stream_id = label.slice("control:".length);
+        const dc = ev.channel;
+        dc.binaryType = "arraybuffer";
+        control_channels[stream_id] = dc;
+        dc.onmessage = e => control_responses.update(r => ({ ...r, [stream_id]: e.data }));
+        dc.onclose = () => {
+            if (control_channels[stream_id] == dc) delete control_channels[stream_id];
+        };
+    }
+};
 
 let batched_signal_timeout = null;
-export async function signal(streams: string[]) {
-    const lo = await pc.createOffer()
+export async function signal(streams: string[], ice_restart = false) {
+    await ice_servers_ready;
+
+    const lo = await pc.createOffer({ iceRestart: ice_restart })
     await pc.setLocalDescription(lo);
 
     console.log(pc.localDescription);
@@ -49,74 +194,100 @@ export async function signal(streams: string[]) {
         body: JSON.stringify({
             uid,
             stream_ids: streams,
-            offer: pc.localDescription
+            offer: pc.localDescription,
+            trickle: true,
+            token: control_token,
         })
     });
 
+    // Our offer collided with one from the server, which arrives over the signalling channel.
+    if (res.status == 409 || pc.signalingState != "have-local-offer") return;
+
+    // ie 503: the server has as many clients as it allows
+    if (!res.ok) {
+        console.error(await res.text());
+        return;
+    }
+
+    pending_candidates?.forEach(send_candidate);
+    pending_candidates = null;
+
     let sd = await res.json();
     console.log(sd);
 
     try {
-        pc.setRemoteDescription(new RTCSessionDescription(sd))
+        await pc.setRemoteDescription(new RTCSessionDescription(sd))
     } catch (e) {
         console.error(e)
     }
+
+    update_track_mids();
+
+    // Candidates are only gathered again after an ICE restart.
+    if (ice_restart || !server_candidates) listen_server_candidates();
 }
 
-export const media_streams = readable({}, (set) => {
-    let s = {};
+// Which stream each of our transceivers (by mid) is receiving. The server reuses
+// transceivers, so a track's msid doesn't say which stream it's currently showing.
+const track_mids = writable<Record<string, string>>({});
 
-    function on_track_unmute(id, stream) {
-        s[id] = stream;
-        set(s);
+async function update_track_mids() {
+    let mids: Record<string, string>;
+    try {
+        mids = await (await fetch(`${API_TRACKS}?uid=${uid}`)).json();
+    } catch (e) {
+        console.error(e);
+        return;
     }
 
-    function on_track_mute(id, stream) {
-        delete s[id];
-        set(s);
-    }
+    // Start streams that weren't being received before
+    let previous = new Set(Object.values(get(track_mids)));
+    let started = Object.values(mids).filter(id => !previous.has(id));
+    track_mids.set(mids);
+
+    if (started.length == 0) return;
+    fetch(`/api/resync`, {
+        method: "POST",
+        body: JSON.stringify({
+            uid,
+            stream_ids: started,
+        })
+    });
+}
+
+// Tracks that are currently receiving media, by transceiver mid
+const live_tracks = readable<Record<string, MediaStream>>({}, (set) => {
+    let s = {};
 
     function on_track(ev: RTCTrackEvent) {
         console.log("NEW TRACK")
         console.log(ev);
 
-        let stream = ev.streams[0]
-
-        if (!stream) return;
-
-        let id = stream.id;
-
-        if (s[id]) { return }
+        let mid = ev.transceiver.mid;
+        let stream = new MediaStream([ev.track]);
 
-        // Init this new media stream
-        //stream.onremovetrack = on_remove_track;
-        ev.track.onunmute = () => on_track_unmute(id, stream);
-        ev.track.onmute = () => on_track_mute(id, stream);
-
-        // Starts the stream
-        fetch(`/api/resync`, {
-            method: "POST",
-            body: JSON.stringify({
-                uid,
-                stream_ids: [stream.id],
-            })
-        });
-    }
-
-    function on_remove_track(ev: MediaStreamTrackEvent) {
-        // Removed the video. The parent mediastream is useless now.
-        // Delete it.
-        console.log(`REMOVE TRACK ${this.id}`)
-        let id = this.id;
-        if (ev.track.kind == 'video') {
-            delete s[id];
-        }
-        set(s);
+        ev.track.onunmute = () => {
+            s[mid] = stream;
+            set(s);
+        };
+        ev.track.onmute = () => {
+            delete s[mid];
+            set(s);
+        };
     }
 
     pc.ontrack = on_track;
 });
 
+// Media streams by stream ID
+export const media_streams = derived([live_tracks, track_mids], ([live, mids]) => {
+    let s = {};
+    for (let [mid, stream] of Object.entries(live)) {
+        if (mids[mid]) s[mids[mid]] = stream;
+    }
+    return s;
+});
+
 selected_stream_ids.subscribe(ids => {
     if (batched_signal_timeout) {
         clearTimeout(batched_signal_timeout);
@@ -125,6 +296,24 @@ selected_stream_ids.subscribe(ids => {
 });
 
 
+// Picks the rendition (ie "main" or "480p") of a viewed stream's video.
+// null lets the server pick, depending on our bandwidth.
+export async function set_rendition(stream_id: string, rendition: string | null) {
+    await fetch(API_RENDITION, {
+        method: "POST",
+        body: JSON.stringify({ uid, stream_id, rendition }),
+    });
+}
+
+// Switches viewed streams to thumbnail mode (keyframes only, at most one every interval_s seconds),
+// ie for grid views. null goes back to full rate, ie when a tile is focused.
+export async function set_thumbnail(stream_ids: string[], interval_s: number | null) {
+    await fetch(API_THUMBNAIL, {
+        method: "POST",
+        body: JSON.stringify({ uid, stream_ids, interval_s }),
+    });
+}
+
 export async function add_stream(id: string) {
     if (pc.getTransceivers().length <= get(selected_stream_ids).length) {
         pc.addTransceiver('video');
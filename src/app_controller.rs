@@ -1,58 +1,244 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
     sync::{Arc, Weak},
+    time::Duration,
 };
 
 use crate::{
     client::Client,
+    client_events::ClientEvent,
+    client_info::ClientInfo,
+    ingest_health::TrackStateEvent,
+    reorder_buffer::ReorderStats,
+    rtc_config::{BrowserIceServer, RtcConfig},
     stats::{SystemStatus, SystemStatusReader},
-    stream_manager::StreamManager,
+    stream_manager::{StreamInfo, StreamManager},
     StreamDef,
 };
-use anyhow::Result;
-use serde::Serialize;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 use tokio::sync::{broadcast, RwLock};
-use webrtc::peer_connection::{
-    peer_connection_state::RTCPeerConnectionState, sdp::session_description::RTCSessionDescription,
+use tokio::time::{interval, Instant};
+use webrtc::{
+    api::API,
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::{
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription,
+    },
 };
 
+// How long clients get to receive the shutdown event before their connections are closed.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+
+// Interval between checks for clients to reap.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+const DEFAULT_CONNECT_TIMEOUT_S: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT_S: u64 = 5 * 60;
+
+/**
+ * Limits on clients. Clients that don't connect in time, or that sit
+ * idle (viewing no streams) for too long, are discarded.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct ClientLimits {
+    max_clients: Option<usize>, // Max concurrent clients. Default is unlimited
+    connect_timeout_s: Option<u64>, // How long new clients have to connect. Default is 30s
+    idle_timeout_s: Option<u64>, // How long clients may view no streams. Default is 5 minutes
+}
+
+impl ClientLimits {
+    fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_s.unwrap_or(DEFAULT_CONNECT_TIMEOUT_S))
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_s.unwrap_or(DEFAULT_IDLE_TIMEOUT_S))
+    }
+}
+
+/**
+ * Returned when a new client would exceed the max concurrent clients.
+ */
+#[derive(Debug)]
+pub struct ClientLimitReached(usize);
+
+impl fmt::Display for ClientLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Too many clients (max {})", self.0)
+    }
+}
+
+impl std::error::Error for ClientLimitReached {}
+
 #[derive(Serialize, Clone)]
 pub struct AppStats {
     system_status: SystemStatus,
     clients: usize,
+    reorder: HashMap<String, ReorderStats>,
 }
 pub struct AppController {
     stream_manager: StreamManager,
 
     clients: Arc<RwLock<HashMap<String, Arc<Client>>>>,
     sys_stats: SystemStatusReader,
+    rtc_config: RtcConfig,
+    limits: ClientLimits,
+    api: API,
 }
 
 impl AppController {
-    pub fn new(stream_manager: StreamManager) -> AppController {
-        AppController {
+    pub fn new(
+        stream_manager: StreamManager,
+        rtc_config: RtcConfig,
+        limits: ClientLimits,
+    ) -> Result<AppController> {
+        let clients = Arc::new(RwLock::new(HashMap::new()));
+
+        AppController::task_stream_event_forwarder(
+            Arc::downgrade(&clients),
+            stream_manager.subscribe_events(),
+        );
+        AppController::task_reaper(Arc::downgrade(&clients), limits.clone());
+
+        Ok(AppController {
             stream_manager,
             sys_stats: SystemStatusReader::new(),
-            clients: Arc::new(RwLock::new(HashMap::new())),
-        }
+            clients,
+            api: rtc_config.build_api()?,
+            rtc_config,
+            limits,
+        })
     }
 
-    pub async fn ensure_client(&self, client_id: &String) -> Result<Arc<Client>> {
-        let client_exists = self.clients.read().await.contains_key(client_id);
-
-        if !client_exists {
-            self.initialize_client(client_id).await
-        } else {
-            let clients_guard = self.clients.read().await;
-            let client_arc = clients_guard.get(client_id).unwrap();
-            Ok(client_arc.clone())
+    /**
+     * Discards clients that never connected in time, or have been idle for too long.
+     * Without this, a browser that signals and vanishes before connecting would
+     * leak its peer connection forever.
+     */
+    fn task_reaper(clients: Weak<RwLock<HashMap<String, Arc<Client>>>>, limits: ClientLimits) {
+        tokio::spawn(async move {
+            let mut interval = interval(REAP_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let clients = match clients.upgrade() {
+                    Some(c) => c,
+                    None => break,
+                };
+
+                let now = Instant::now();
+                let reaped: Vec<Arc<Client>> = {
+                    let mut m = clients.write().await;
+                    let expired: Vec<String> = m
+                        .iter()
+                        .filter_map(|(id, c)| {
+                            if c.unconnected_for(now)
+                                .is_some_and(|d| d > limits.connect_timeout())
+                            {
+                                println!("Reaping client {}: never connected", id);
+                            } else if c.idle_for(now).is_some_and(|d| d > limits.idle_timeout()) {
+                                println!("Reaping client {}: idle", id);
+                            } else {
+                                return None;
+                            }
+                            Some(id.clone())
+                        })
+                        .collect();
+
+                    expired
+                        .into_iter()
+                        .filter_map(|id| m.remove(&id))
+                        .collect()
+                };
+
+                for c in reaped {
+                    c.discard().await;
+                }
+            }
+        });
+    }
+
+    /**
+     * Forwards stream state changes to the clients viewing the stream.
+     */
+    fn task_stream_event_forwarder(
+        clients: Weak<RwLock<HashMap<String, Arc<Client>>>>,
+        mut events: broadcast::Receiver<TrackStateEvent>,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                let ev = match events.recv().await {
+                    Ok(ev) => ev,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                let clients = match clients.upgrade() {
+                    Some(c) => c,
+                    None => break,
+                };
+
+                for c in clients.read().await.values() {
+                    if c.stream_ids().await.contains(&ev.stream_id) {
+                        c.send_event(ClientEvent::StreamState {
+                            stream_id: ev.stream_id.clone(),
+                            track: ev.track.clone(),
+                            state: ev.state,
+                        });
+                    }
+                }
+            }
+        });
+    }
+
+    /**
+     * Tells every client the server is going away, then closes their connections.
+     */
+    pub async fn shutdown(&self) {
+        let clients: Vec<Arc<Client>> = self
+            .clients
+            .write()
+            .await
+            .drain()
+            .map(|(_, c)| c)
+            .collect();
+
+        for c in clients.iter() {
+            c.send_event(ClientEvent::Shutdown);
+        }
+
+        // Give the events a moment to go out before the channels close.
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+
+        for c in clients {
+            c.discard().await;
         }
     }
 
-    pub async fn initialize_client(&self, client_id: &String) -> Result<Arc<Client>> {
+    /**
+     * Returns the client with the passed ID, creating it if it doesn't exist yet.
+     * The lookup and insert happen under one lock, so concurrent requests for
+     * a new ID can't each create their own client.
+     */
+    pub async fn ensure_client(&self, client_id: &String) -> Result<Arc<Client>> {
         let mut m = self.clients.write().await;
-        let c = Arc::new(Client::new().await?);
-        m.insert(client_id.clone(), c.clone());
+        let count = m.len();
+        let entry = match m.entry(client_id.clone()) {
+            Entry::Occupied(e) => return Ok(e.get().clone()),
+            Entry::Vacant(e) => e,
+        };
+
+        if let Some(max) = self.limits.max_clients {
+            if count >= max {
+                return Err(ClientLimitReached(max).into());
+            }
+        }
+        let c = entry
+            .insert(Arc::new(Client::new(&self.api, &self.rtc_config).await?))
+            .clone();
         drop(m);
 
         // Spawn kill watcher. Deallocs and cleans up after clients are closed.
@@ -61,12 +247,16 @@ impl AppController {
         let clients = self.clients.clone();
         tokio::spawn(async move {
             // Wait for client to fail
-            c_inner.watch_fail().changed().await.unwrap();
-            drop(c_inner);
+            c_inner.watch_fail().changed().await.ok();
 
-            // Remove the client from our list
+            // Remove the client from our list, unless it was already discarded
+            // (and possibly replaced by a new client with the same ID).
             let mut m = clients.write().await;
-            let c = m.remove(&id_inner).unwrap();
+            let c = match m.get(&id_inner) {
+                Some(c) if Arc::ptr_eq(c, &c_inner) => m.remove(&id_inner).unwrap(),
+                _ => return,
+            };
+            drop(c_inner);
 
             // Finalize the client and drop it.
             // This should deallocate the client (strong arc = 0)
@@ -84,27 +274,135 @@ impl AppController {
         &self,
         client_id: &String,
         offer: RTCSessionDescription,
-    ) -> Result<RTCSessionDescription> {
+        trickle: bool,
+    ) -> Result<Option<RTCSessionDescription>> {
         let c = self.ensure_client(client_id).await?;
-        let res = c.signal(offer).await;
+        let res = c.signal(offer, trickle).await;
         if res.is_err() {
-            self.discard_client(&client_id).await;
+            self.discard_client(&client_id).await.ok();
         }
 
         res
     }
 
-    async fn discard_client(&self, client_id: &String) {
+    /**
+     * Returns an already-signalled client. Unlike ensure_client,
+     * unknown clients are an error rather than being created.
+     */
+    async fn get_client(&self, client_id: &String) -> Result<Arc<Client>> {
+        self.clients
+            .read()
+            .await
+            .get(client_id)
+            .cloned()
+            .ok_or(anyhow::Error::msg("Unknown client"))
+    }
+
+    pub async fn add_ice_candidate(
+        &self,
+        client_id: &String,
+        candidate: RTCIceCandidateInit,
+    ) -> Result<()> {
+        // Only clients that have signalled get candidates. Creating clients here would let
+        // anyone make them for arbitrary uids.
+        self.get_client(client_id)
+            .await?
+            .add_ice_candidate(candidate)
+            .await
+    }
+
+    pub async fn set_client_token(&self, client_id: &String, token: Option<String>) -> Result<()> {
+        self.get_client(client_id)
+            .await?
+            .set_control_token(token)
+            .await;
+        Ok(())
+    }
+
+    /**
+     * Sets the rendition of a stream's video a client is sent. None picks it automatically.
+     */
+    pub async fn client_set_rendition(
+        &self,
+        client_id: &String,
+        stream_id: &str,
+        rendition: Option<String>,
+    ) -> Result<()> {
+        self.get_client(client_id)
+            .await?
+            .set_rendition(stream_id, rendition)
+            .await
+    }
+
+    /**
+     * Pauses (or resumes) sending a client's streams, without renegotiating.
+     */
+    pub async fn client_set_paused(
+        &self,
+        client_id: &String,
+        stream_ids: &[String],
+        paused: bool,
+    ) -> Result<()> {
+        let c = self.get_client(client_id).await?;
+        for id in stream_ids {
+            c.set_stream_paused(id, paused).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Switches a client's streams to thumbnail mode, sending only keyframes
+     * at most once per passed interval, or back to full rate (None).
+     */
+    pub async fn client_set_thumbnail(
+        &self,
+        client_id: &String,
+        stream_ids: &[String],
+        interval: Option<Duration>,
+    ) -> Result<()> {
+        let c = self.get_client(client_id).await?;
+        for id in stream_ids {
+            c.set_thumbnail(id, interval).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn client_track_mids(&self, client_id: &String) -> Result<HashMap<String, String>> {
+        Ok(self.get_client(client_id).await?.track_mids().await)
+    }
+
+    pub async fn subscribe_ice_candidates(
+        &self,
+        client_id: &String,
+    ) -> Result<(
+        Vec<Option<RTCIceCandidateInit>>,
+        broadcast::Receiver<Option<RTCIceCandidateInit>>,
+    )> {
+        Ok(self
+            .get_client(client_id)
+            .await?
+            .subscribe_ice_candidates()
+            .await)
+    }
+
+    pub async fn has_client(&self, client_id: &String) -> bool {
+        self.clients.read().await.contains_key(client_id)
+    }
+
+    pub async fn discard_client(&self, client_id: &String) -> Result<()> {
         let mut clients = self.clients.write().await;
 
         // Remove the client from our list
-        let c = clients.remove(client_id).unwrap();
+        let c = clients
+            .remove(client_id)
+            .ok_or(anyhow::Error::msg("Unknown client"))?;
 
         // Finalize the client and drop it.
         // This should deallocate the client (strong arc = 0)
         // which should stop any related tasks holding a weak as well
         c.discard().await;
         drop(c);
+        Ok(())
     }
 
     // // Use client_sync_streams to emulate this functionality.
@@ -153,6 +451,68 @@ impl AppController {
         Ok(())
     }
 
+    /**
+     * Disconnects a client (ie an operator removing a viewer). It's told why
+     * first, so it doesn't take it for a dropped connection and reconnect.
+     */
+    pub async fn kick_client(&self, client_id: &String) -> Result<()> {
+        let c = self
+            .clients
+            .write()
+            .await
+            .remove(client_id)
+            .ok_or(anyhow::Error::msg("Unknown client"))?;
+
+        c.send_event(ClientEvent::Kicked);
+
+        // Give the event a moment to go out before the channels close.
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+        c.discard().await;
+        Ok(())
+    }
+
+    /**
+     * Restarts a client's streams from their fast-start buffers (ie to unstick a wall display).
+     * Unlike client_resync_streams, the client must already exist and view the streams.
+     */
+    pub async fn admin_resync_streams(
+        &self,
+        client_id: &String,
+        stream_ids: Vec<String>,
+    ) -> Result<()> {
+        let c = self.get_client(client_id).await?;
+
+        let viewed = c.stream_ids().await;
+        if let Some(id) = stream_ids.iter().find(|id| !viewed.contains(*id)) {
+            bail!("Client isn't viewing {}", id);
+        }
+
+        for id in stream_ids {
+            c.resync_stream(id).await;
+        }
+        Ok(())
+    }
+
+    /**
+     * Sets the streams an existing client views (ie a control room driving wall displays).
+     * Transceivers are renegotiated over the client's signalling channel if needed,
+     * and the client is told its new stream set.
+     */
+    pub async fn assign_streams(&self, client_id: &String, stream_ids: Vec<String>) -> Result<()> {
+        let c = self.get_client(client_id).await?;
+
+        if let Some(id) = stream_ids
+            .iter()
+            .find(|id| self.stream_manager.get_stream(id).is_none())
+        {
+            bail!("Unknown stream {}", id);
+        }
+
+        self.client_sync_streams(client_id, stream_ids.clone()).await?;
+        c.send_event(ClientEvent::StreamsAssigned { stream_ids });
+        Ok(())
+    }
+
     pub async fn client_resync_streams(&self, client_id: &String, stream_ids: Vec<String>) -> Result<()> {
         let c = self.ensure_client(&client_id).await?;
 
@@ -167,14 +527,54 @@ impl AppController {
 
     fn delete_stream(_id: String) {}
 
-    pub fn streams(&self) -> Vec<StreamDef> {
-        self.stream_manager.stream_defs()
+    pub fn has_stream(&self, stream_id: &String) -> bool {
+        self.stream_manager.get_stream(stream_id).is_some()
+    }
+
+    pub async fn streams(&self) -> Vec<StreamInfo> {
+        self.stream_manager.stream_infos().await
+    }
+
+    pub fn subscribe_stream_events(&self) -> broadcast::Receiver<TrackStateEvent> {
+        self.stream_manager.subscribe_events()
+    }
+
+    /**
+     * ICE servers for browsers to use, matching the ones our peer connections use.
+     */
+    pub fn ice_servers(&self) -> Vec<BrowserIceServer> {
+        self.rtc_config.browser_ice_servers()
+    }
+
+    /**
+     * Details about every client's connection and streams.
+     */
+    pub async fn client_infos(&self) -> Vec<ClientInfo> {
+        let clients: Vec<(String, Arc<Client>)> = self
+            .clients
+            .read()
+            .await
+            .iter()
+            .map(|(id, c)| (id.clone(), c.clone()))
+            .collect();
+
+        let mut infos = Vec::new();
+        for (id, c) in clients {
+            infos.push(c.info(&id).await);
+        }
+        infos.sort_by(|a, b| a.age_s.total_cmp(&b.age_s).reverse());
+        infos
+    }
+
+    pub async fn client_info(&self, client_id: &String) -> Result<ClientInfo> {
+        Ok(self.get_client(client_id).await?.info(client_id).await)
     }
 
     pub async fn stats(&self) -> AppStats {
         AppStats {
             system_status: self.sys_stats.stats().await,
             clients: self.clients.read().await.len(),
+            reorder: self.stream_manager.reorder_stats().await,
         }
     }
 }
@@ -0,0 +1,287 @@
+// Per-client bandwidth estimation, from the feedback browsers send about our packets:
+// REMB (the browser's own estimate), transport-wide congestion control (TWCC) reports
+// and receiver reports. Loss drives a GCC-style estimate, capped by REMB if the browser sends it.
+// https://datatracker.ietf.org/doc/html/draft-ietf-rmcat-gcc-02#section-6
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+use webrtc::rtcp::{
+    payload_feedbacks::receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    transport_feedbacks::transport_layer_cc::{PacketStatusChunk, SymbolTypeTcc, TransportLayerCc},
+};
+
+// Clients start out assumed to have plenty of bandwidth, so streams aren't held back
+// until there's evidence (loss) they can't keep up.
+const MAX_ESTIMATE_BPS: f64 = 50_000_000.0;
+const MIN_ESTIMATE_BPS: f64 = 100_000.0;
+
+// Loss below which the estimate grows, and above which it shrinks.
+const LOW_LOSS: f64 = 0.02;
+const HIGH_LOSS: f64 = 0.10;
+
+// Growth of the estimate per update, while loss is low.
+const INCREASE_FACTOR: f64 = 1.05;
+
+// How long a REMB value caps the estimate for. Browsers send them about once a second.
+const REMB_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BandwidthStats {
+    estimate_bps: f64,     // Estimated available bandwidth
+    send_bps: f64,         // What's actually being sent to the client
+    loss: f64,             // Fraction of packets lost, over the last update
+    remb_bps: Option<f64>, // The browser's own estimate, if it sends one
+}
+
+impl Default for BandwidthStats {
+    fn default() -> Self {
+        BandwidthStats {
+            estimate_bps: MAX_ESTIMATE_BPS,
+            send_bps: 0.0,
+            loss: 0.0,
+            remb_bps: None,
+        }
+    }
+}
+
+impl BandwidthStats {
+    pub fn estimate_bps(&self) -> f64 {
+        self.estimate_bps
+    }
+}
+
+/**
+ * Bandwidth estimate for a client. Fed by the client's RTCP readers, and
+ * updated periodically with what's been sent.
+ */
+pub struct BandwidthEstimator {
+    loss_estimate_bps: f64,
+    remb: Option<(f64, Instant)>,
+    twcc_received: u64, // Packets reported by TWCC since the last update
+    twcc_lost: u64,
+    last_sent: Option<(u64, Instant)>, // Total bytes sent at the last update
+    stats: BandwidthStats,
+}
+
+impl BandwidthEstimator {
+    pub fn new() -> BandwidthEstimator {
+        BandwidthEstimator {
+            loss_estimate_bps: MAX_ESTIMATE_BPS,
+            remb: None,
+            twcc_received: 0,
+            twcc_lost: 0,
+            last_sent: None,
+            stats: BandwidthStats::default(),
+        }
+    }
+
+    pub fn record_remb(&mut self, remb: &ReceiverEstimatedMaximumBitrate, now: Instant) {
+        self.remb = Some((remb.bitrate as f64, now));
+    }
+
+    /**
+     * Counts the packets a TWCC report says were received and lost.
+     */
+    pub fn record_twcc(&mut self, cc: &TransportLayerCc) {
+        let symbols = cc.packet_chunks.iter().flat_map(|chunk| match chunk {
+            PacketStatusChunk::RunLengthChunk(c) => {
+                vec![c.packet_status_symbol; c.run_length as usize]
+            }
+            PacketStatusChunk::StatusVectorChunk(c) => c.symbol_list.clone(),
+        });
+
+        // The last status vector may be padded past the reported packets.
+        for symbol in symbols.take(cc.packet_status_count as usize) {
+            match symbol {
+                SymbolTypeTcc::PacketNotReceived => self.twcc_lost += 1,
+                _ => self.twcc_received += 1,
+            }
+        }
+    }
+
+    /**
+     * Updates the estimate from the feedback since the last update.
+     * `bytes_sent` is the total sent to the client so far, and `rr_loss` the loss
+     * from its latest receiver reports, used if it doesn't send TWCC reports.
+     */
+    pub fn update(&mut self, bytes_sent: u64, rr_loss: f64, now: Instant) -> BandwidthStats {
+        let send_bps = match self.last_sent {
+            Some((last, at)) if now > at => {
+                bytes_sent.saturating_sub(last) as f64 * 8.0 / now.duration_since(at).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        self.last_sent = Some((bytes_sent, now));
+
+        let reported = self.twcc_received + self.twcc_lost;
+        let loss = match reported {
+            0 => rr_loss,
+            _ => self.twcc_lost as f64 / reported as f64,
+        };
+        self.twcc_received = 0;
+        self.twcc_lost = 0;
+
+        // Loss based controller. Low loss grows the estimate, high loss cuts it in
+        // proportion to the loss, relative to what was actually being sent.
+        if loss > HIGH_LOSS {
+            let base = match send_bps > 0.0 {
+                true => send_bps.min(self.loss_estimate_bps),
+                false => self.loss_estimate_bps,
+            };
+            self.loss_estimate_bps = base * (1.0 - 0.5 * loss);
+        } else if loss < LOW_LOSS {
+            self.loss_estimate_bps *= INCREASE_FACTOR;
+        }
+        self.loss_estimate_bps = self
+            .loss_estimate_bps
+            .clamp(MIN_ESTIMATE_BPS, MAX_ESTIMATE_BPS);
+
+        let remb_bps = self
+            .remb
+            .filter(|(_, at)| now.duration_since(*at) < REMB_TIMEOUT)
+            .map(|(bps, _)| bps);
+
+        self.stats = BandwidthStats {
+            estimate_bps: remb_bps
+                .map_or(self.loss_estimate_bps, |r| r.min(self.loss_estimate_bps)),
+            send_bps,
+            loss,
+            remb_bps,
+        };
+        self.stats
+    }
+
+    pub fn stats(&self) -> BandwidthStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtcp::transport_feedbacks::transport_layer_cc::{
+        RunLengthChunk, StatusVectorChunk,
+    };
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1.0,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    /**
+     * An estimator that has sent at 10 Mbps for a second.
+     */
+    fn sending_10mbps(start: Instant) -> BandwidthEstimator {
+        let mut e = BandwidthEstimator::new();
+        e.update(0, 0.0, start);
+        e
+    }
+
+    #[test]
+    fn starts_unconstrained() {
+        let mut e = BandwidthEstimator::new();
+        assert_eq!(e.stats().estimate_bps(), MAX_ESTIMATE_BPS);
+        assert_eq!(
+            e.update(0, 0.0, Instant::now()).estimate_bps(),
+            MAX_ESTIMATE_BPS
+        );
+    }
+
+    #[test]
+    fn high_loss_cuts_from_send_rate() {
+        let start = Instant::now();
+        let mut e = sending_10mbps(start);
+
+        let stats = e.update(1_250_000, 0.2, start + Duration::from_secs(1));
+        assert_near(stats.send_bps, 10_000_000.0);
+        assert_near(stats.estimate_bps(), 9_000_000.0);
+    }
+
+    #[test]
+    fn loss_thresholds() {
+        let start = Instant::now();
+        let mut e = sending_10mbps(start);
+        e.update(1_250_000, 0.2, start + Duration::from_secs(1));
+
+        // Between the thresholds (inclusive), the estimate holds.
+        let stats = e.update(2_500_000, LOW_LOSS, start + Duration::from_secs(2));
+        assert_near(stats.estimate_bps(), 9_000_000.0);
+        let stats = e.update(3_750_000, HIGH_LOSS, start + Duration::from_secs(3));
+        assert_near(stats.estimate_bps(), 9_000_000.0);
+
+        // Past them it shrinks, or grows.
+        let stats = e.update(5_000_000, 0.11, start + Duration::from_secs(4));
+        assert!(stats.estimate_bps() < 9_000_000.0);
+        let cut = stats.estimate_bps();
+        let stats = e.update(6_250_000, 0.01, start + Duration::from_secs(5));
+        assert_near(stats.estimate_bps(), cut * INCREASE_FACTOR);
+    }
+
+    #[test]
+    fn estimate_never_below_minimum() {
+        let start = Instant::now();
+        let mut e = BandwidthEstimator::new();
+        for i in 0..100 {
+            e.update(i * 1000, 1.0, start + Duration::from_secs(i));
+        }
+        assert_eq!(e.stats().estimate_bps(), MIN_ESTIMATE_BPS);
+    }
+
+    #[test]
+    fn twcc_loss_overrides_receiver_reports() {
+        let start = Instant::now();
+        let mut e = BandwidthEstimator::new();
+
+        // 8 received, then 2 lost. The rest of the status vector is padding.
+        e.record_twcc(&TransportLayerCc {
+            packet_status_count: 10,
+            packet_chunks: vec![
+                PacketStatusChunk::RunLengthChunk(RunLengthChunk {
+                    packet_status_symbol: SymbolTypeTcc::PacketReceivedSmallDelta,
+                    run_length: 8,
+                    ..Default::default()
+                }),
+                PacketStatusChunk::StatusVectorChunk(StatusVectorChunk {
+                    symbol_list: vec![
+                        SymbolTypeTcc::PacketNotReceived,
+                        SymbolTypeTcc::PacketNotReceived,
+                        SymbolTypeTcc::PacketNotReceived,
+                        SymbolTypeTcc::PacketNotReceived,
+                    ],
+                    ..Default::default()
+                }),
+            ],
+            ..Default::default()
+        });
+        assert_near(e.update(0, 0.0, start).loss * 100.0, 20.0);
+
+        // Without new TWCC reports, receiver reports are used.
+        assert_near(e.update(0, 0.05, start).loss * 100.0, 5.0);
+    }
+
+    #[test]
+    fn remb_caps_estimate_until_stale() {
+        let start = Instant::now();
+        let mut e = BandwidthEstimator::new();
+        e.record_remb(
+            &ReceiverEstimatedMaximumBitrate {
+                bitrate: 2_000_000.0,
+                ..Default::default()
+            },
+            start,
+        );
+
+        let stats = e.update(0, 0.0, start + Duration::from_secs(1));
+        assert_eq!(stats.remb_bps, Some(2_000_000.0));
+        assert_eq!(stats.estimate_bps(), 2_000_000.0);
+
+        let stats = e.update(0, 0.0, start + REMB_TIMEOUT);
+        assert_eq!(stats.remb_bps, None);
+        assert_eq!(stats.estimate_bps(), MAX_ESTIMATE_BPS);
+    }
+}
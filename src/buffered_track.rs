@@ -1,6 +1,11 @@
-use std::sync::{Arc, Weak};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use tokio::select;
+use tokio::time::{timeout, Instant};
 
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{mpsc, Notify};
 use webrtc::rtp::packet::Packet;
 use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
@@ -10,12 +15,46 @@ use webrtc::track::track_local::TrackLocalWriter;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 
+use crate::client_events::ClientEvent;
+use crate::rewriter::{Rewriter, SentPosition};
 use crate::rtp_track::RtpTrack;
+use crate::thumbnail::KeyframeSampler;
+use crate::TrackDef;
+
+// How long a rendition switch waits for the new rendition's next keyframe before giving up.
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Minimum time between keyframe replays. Each one re-sends everything since the last keyframe,
+// and browsers repeat PLIs until they get a keyframe.
+const KEYFRAME_REPLAY_INTERVAL: Duration = Duration::from_secs(2);
+
+// Keyframe requests are left to the source if its next keyframe is expected within this long.
+const KEYFRAME_DUE_SOON: Duration = Duration::from_secs(1);
+
 pub struct BufferedTrack {
     pub rtc_track: Arc<TrackLocalStaticRTP>,
-    pub rtp_track: Weak<RtpTrack>,
+    rtp_track: Mutex<Weak<RtpTrack>>, // Changes when switching renditions
+    switches: AtomicU64, // Incremented by every rendition switch, so superseded ones are abandoned
 
     controls: Arc<TaskControls>,
+    paused: AtomicBool, // Set while stopped, so a missed stop notification can't leave it sending
+    thumbnail: Mutex<Option<Duration>>, // Only keyframes are sent (at most one per interval) if set
+    resume_from_keyframe: AtomicBool, // Set by resume, so the next start skips to the latest keyframe
+    last_replay: Mutex<Option<Instant>>, // When the last keyframe was replayed
+    rewriter: Mutex<Rewriter>, // Re-sent packets continue on from whatever the client last received
+    events: mpsc::UnboundedSender<ClientEvent>, // The owning client's events
+    lag: Arc<Mutex<LagStats>>, // Shared by all of the owning client's tracks
+}
+
+/**
+ * How often a client fell behind its tracks' broadcasts (ie on a slow link),
+ * and how many packets it missed because of it.
+ */
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct LagStats {
+    lag_events: u64,      // Times a track's broadcast dropped packets the client hadn't sent yet
+    lagged_packets: u64,  // Packets dropped by the broadcast
+    dropped_packets: u64, // Packets skipped waiting for the next keyframe after lagging
 }
 
 #[derive(Default)]
@@ -23,6 +62,8 @@ pub struct TaskControls {
     play: Notify,
     stop: Notify,
     kill: Notify,
+    keyframe: Notify,
+    switch: Notify, // Restarts from the latest keyframe, after a rendition or mode switch
 }
 
 /**
@@ -30,7 +71,11 @@ pub struct TaskControls {
  * and Webrtc-rs's TrackLocalStaticRTP. Supports "fast-starting" a remote client.
  */
 impl BufferedTrack {
-    pub fn new(rtp_track: Arc<RtpTrack>) -> Arc<BufferedTrack> {
+    pub fn new(
+        rtp_track: Arc<RtpTrack>,
+        events: mpsc::UnboundedSender<ClientEvent>,
+        lag: Arc<Mutex<LagStats>>,
+    ) -> Arc<BufferedTrack> {
         let suffix: String = thread_rng()
             .sample_iter(&Alphanumeric)
             .take(5)
@@ -54,8 +99,16 @@ impl BufferedTrack {
 
         let buffered_track = Arc::new(BufferedTrack {
             rtc_track: rtc_track.clone(),
-            rtp_track: Arc::downgrade(&rtp_track),
+            rtp_track: Mutex::new(Arc::downgrade(&rtp_track)),
+            switches: AtomicU64::new(0),
             controls: Arc::new(TaskControls::default()),
+            paused: AtomicBool::new(false),
+            thumbnail: Mutex::new(None),
+            resume_from_keyframe: AtomicBool::new(false),
+            last_replay: Mutex::new(None),
+            rewriter: Mutex::new(Rewriter::new(rtp_track.track_def.clock_rate())),
+            events,
+            lag,
         });
 
         BufferedTrack::pusher_task(Arc::downgrade(&buffered_track));
@@ -70,20 +123,46 @@ impl BufferedTrack {
     fn pusher_task(buffered_track: Weak<BufferedTrack>) {
         tokio::spawn(async move {
             let controls = buffered_track.upgrade().unwrap().controls.clone();
+            let mut keyframe_requested = false;
+
             'main: loop {
                 // Wait for play before doing anything.
-                controls.play.notified().await;
+                // Keyframe replays restart immediately, as the track was already playing.
+                let paused = match buffered_track.upgrade() {
+                    Some(track) => track.paused.load(Ordering::Relaxed),
+                    None => break 'main,
+                };
+                if !keyframe_requested || paused {
+                    controls.play.notified().await;
+                }
 
                 // Re-initialize on every iteration
-                let rtp_track = buffered_track
-                    .upgrade()
-                    .unwrap()
-                    .rtp_track
-                    .upgrade()
-                    .unwrap();
-
-                let faststart_buf = rtp_track.ff_buf().await;
+                let (rtp_track, thumbnail) = match buffered_track.upgrade() {
+                    Some(track) => {
+                        keyframe_requested |=
+                            track.resume_from_keyframe.swap(false, Ordering::Relaxed);
+                        (track.rtp_track().unwrap(), *track.thumbnail.lock().unwrap())
+                    }
+                    None => break 'main,
+                };
+
+                // Thumbnails only need the latest keyframe to start from.
+                let mut sampler = thumbnail.map(KeyframeSampler::new);
+                let faststart_buf = if keyframe_requested || sampler.is_some() {
+                    rtp_track.ff_buf_from_last_keyframe().await
+                } else {
+                    rtp_track.ff_buf().await
+                };
+                keyframe_requested = false;
+
+                // Re-sent packets continue on from whatever the client last received.
+                match buffered_track.upgrade() {
+                    Some(track) => track.rewriter.lock().unwrap().restart(),
+                    None => break 'main,
+                }
                 let mut rtp_subscription = rtp_track.subscribe();
+                let stream_id = rtp_track.stream_def.id.clone();
+                let track_def = rtp_track.track_def.clone();
                 drop(rtp_track); // drop the rtp track arc after each iter so we don't keep it uncollected.
 
                 // Initialize the faststart buffer.
@@ -99,6 +178,15 @@ impl BufferedTrack {
                     faststart_tx.send(pkt.clone()).unwrap();
                 }
 
+                // The client is told when the burst is done, so it can hide the fast-forwarding.
+                let mut faststart_remaining = faststart_buf.len();
+                if faststart_remaining == 0 {
+                    BufferedTrack::fast_start_done(&buffered_track, &stream_id);
+                }
+
+                // Set after falling behind the broadcast, until the next keyframe arrives.
+                let mut awaiting_keyframe = false;
+
                 // Reference: https://github.com/meetecho/janus-gateway/blob/master/src/postprocessing/pp-h264.c
                 // https://webrtchacks.com/what-i-learned-about-h-264-for-webrtc-video-tim-panton/
                 // https://github.com/steely-glint/srtplight
@@ -108,6 +196,8 @@ impl BufferedTrack {
                         let rtp_track_recv = rtp_subscription.recv();
                         let killed_recv = controls.kill.notified();
                         let stop_recv = controls.stop.notified();
+                        let keyframe_recv = controls.keyframe.notified();
+                        let switch_recv = controls.switch.notified();
                     };
 
                     select! {
@@ -121,23 +211,84 @@ impl BufferedTrack {
                             break 'inner;
                         }
 
+                        _ = keyframe_recv => {
+                            keyframe_requested = true;
+                            break 'inner;
+                        }
+
+                        // Switched renditions (the new one just broadcast a keyframe)
+                        // or between thumbnail mode and full rate. Restart from the latest keyframe.
+                        _ = switch_recv => {
+                            keyframe_requested = true;
+                            break 'inner;
+                        }
+
                         // All the buffered packets from when this track is created should
                         // be pushed through RTP before any new packets are pushed
                         // because this select is biased. Otherwise problems will happen
                         Some(pkt) = faststart_recv  => {
                             if let Some(track) = buffered_track.upgrade() {
-                                track.rtc_track.write_rtp(&pkt).await.unwrap();
+                                if track.paused.load(Ordering::Relaxed) {
+                                    break 'inner;
+                                }
+                                track.send(pkt, &mut sampler, &track_def).await;
                             } else {
                                 break 'main;
                             }
+
+                            faststart_remaining -= 1;
+                            if faststart_remaining == 0 {
+                                BufferedTrack::fast_start_done(&buffered_track, &stream_id);
+                            }
                         },
 
                         // Once all buffered packets have sent, simply listen on the
                         // broadcast channel for newly received packets
                         // and dispatch them
-                        Ok(pkt) = rtp_track_recv => {
+                        res = rtp_track_recv => {
+                            let pkt = match res {
+                                Ok(pkt) => pkt,
+
+                                // This client fell behind, and the broadcast dropped packets it
+                                // hadn't sent yet. Rather than sending video with holes in it,
+                                // packets are dropped until the next keyframe.
+                                Err(RecvError::Lagged(n)) => {
+                                    println!(
+                                        "Client lagged {} packets behind. Dropping to the next keyframe",
+                                        n
+                                    );
+                                    match buffered_track.upgrade() {
+                                        Some(track) => track.lagged(n).await,
+                                        None => break 'main,
+                                    }
+                                    awaiting_keyframe = true;
+                                    continue 'inner;
+                                }
+
+                                // The track was deleted
+                                Err(RecvError::Closed) => break 'main,
+                            };
+
+                            if awaiting_keyframe {
+                                // Restart from the keyframe (and the parameter sets sent before it),
+                                // which are in the fast-start buffer by the time they're broadcast.
+                                if track_def.is_keyframe(&pkt) {
+                                    keyframe_requested = true;
+                                    break 'inner;
+                                }
+
+                                match buffered_track.upgrade() {
+                                    Some(track) => track.lag.lock().unwrap().dropped_packets += 1,
+                                    None => break 'main,
+                                }
+                                continue 'inner;
+                            }
+
                             if let Some(track) = buffered_track.upgrade() {
-                                track.rtc_track.write_rtp(&pkt).await.unwrap();
+                                if track.paused.load(Ordering::Relaxed) {
+                                    break 'inner;
+                                }
+                                track.send(pkt, &mut sampler, &track_def).await;
                             } else {
                                 break 'main;
                             }
@@ -150,20 +301,270 @@ impl BufferedTrack {
         });
     }
 
+    /**
+     * Sends a packet to the client. In thumbnail mode, packets go through the passed sampler,
+     * and only the frames it picks are sent. Each of those continues on directly from the
+     * previous one, timestamped by when it's sent, so the client plays the keyframes smoothly
+     * as a low frame rate video rather than seeing the skipped frames as loss.
+     */
+    async fn send(
+        &self,
+        pkt: Arc<Packet>,
+        sampler: &mut Option<KeyframeSampler>,
+        track_def: &TrackDef,
+    ) {
+        let frame = match sampler {
+            Some(sampler) => match sampler.push(pkt, track_def, Instant::now()) {
+                Some(frame) => {
+                    self.rewriter.lock().unwrap().restart();
+                    frame
+                }
+                None => return,
+            },
+            None => vec![pkt],
+        };
+
+        for pkt in frame.iter() {
+            let pkt = self.rewriter.lock().unwrap().rewrite(pkt, Instant::now());
+            self.rtc_track.write_rtp(&pkt).await.unwrap();
+        }
+    }
+
+    /**
+     * Records a lag event, and asks the source for a keyframe so the client
+     * doesn't have to wait for the next scheduled one.
+     */
+    async fn lagged(&self, skipped: u64) {
+        {
+            let mut lag = self.lag.lock().unwrap();
+            lag.lag_events += 1;
+            lag.lagged_packets += skipped;
+        }
+
+        if let Some(rtp_track) = self.rtp_track() {
+            rtp_track.request_keyframe().await;
+        }
+    }
+
+    fn fast_start_done(buffered_track: &Weak<BufferedTrack>, stream_id: &str) {
+        if let Some(track) = buffered_track.upgrade() {
+            track
+                .events
+                .send(ClientEvent::FastStartDone {
+                    stream_id: stream_id.to_string(),
+                })
+                .ok();
+        }
+    }
+
     pub async fn play(&self) {
+        self.paused.store(false, Ordering::Relaxed);
         self.controls.play.notify_waiters();
     }
 
+    /**
+     * Stops sending until the track is played or resynced.
+     */
     pub async fn stop(&self) {
-        self.controls.play.notify_waiters();
+        self.paused.store(true, Ordering::Relaxed);
+        self.controls.stop.notify_waiters();
     }
 
     pub async fn resync(&self) {
         println!("RESYNCING");
+        self.paused.store(false, Ordering::Relaxed);
+        self.controls.stop.notify_waiters();
+        self.controls.play.notify_one();
+    }
+
+    /**
+     * Restarts a stopped track from the latest keyframe. Unlike resync, the client
+     * already has the stream's earlier video, so only the current GOP is replayed.
+     */
+    pub async fn resume(&self) {
+        self.resume_from_keyframe.store(true, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
         self.controls.stop.notify_waiters();
         self.controls.play.notify_one();
     }
 
+    /**
+     * Where this track's output left off, so a track replacing it on the same
+     * transceiver can continue from there.
+     */
+    pub fn last_sent(&self) -> Option<SentPosition> {
+        self.rewriter.lock().unwrap().last_out()
+    }
+
+    /**
+     * Makes this track's output continue on from another track's, for when
+     * it replaces that track on a transceiver. Must be called before it starts playing.
+     */
+    pub fn continue_after(&self, last: SentPosition) {
+        self.rewriter.lock().unwrap().continue_after(last);
+    }
+
+    /**
+     * Handles a keyframe request (PLI/FIR) from the client. The request is passed on to
+     * the track's source, if it takes keyframe requests. Unless the source's next keyframe
+     * is due soon anyway, the pusher (if running) is also restarted from the most recent
+     * buffered keyframe, so the client recovers without waiting for the next one.
+     */
+    pub async fn request_keyframe(&self) {
+        let rtp_track = match self.rtp_track() {
+            Some(t) => t,
+            None => return,
+        };
+        rtp_track.request_keyframe().await;
+
+        if rtp_track
+            .next_keyframe_in()
+            .await
+            .is_some_and(|d| d < KEYFRAME_DUE_SOON)
+        {
+            return;
+        }
+
+        {
+            let mut last = self.last_replay.lock().unwrap();
+            if last.is_some_and(|t| t.elapsed() < KEYFRAME_REPLAY_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+
+        println!("Replaying last keyframe");
+        self.controls.keyframe.notify_waiters();
+    }
+
+    /**
+     * Re-sends the packets with the passed (client side) sequence numbers
+     * from the track's retransmission cache, in answer to a NACK.
+     * Returns the number of packets that could be re-sent.
+     */
+    pub async fn retransmit(&self, seqs: &[u16]) -> usize {
+        let rtp_track = match self.rtp_track() {
+            Some(t) => t,
+            None => return 0,
+        };
+
+        let mut pkts = Vec::new();
+        for &seq in seqs {
+            let original = self.rewriter.lock().unwrap().original_seq(seq);
+            let pkt = match original {
+                Some(original) => rtp_track.retransmit_packet(original).await,
+                None => continue,
+            };
+
+            if let Some(pkt) = pkt {
+                pkts.push(self.rewriter.lock().unwrap().apply(&pkt));
+            }
+        }
+
+        for pkt in pkts.iter() {
+            if let Err(e) = self.rtc_track.write_rtp(pkt).await {
+                eprintln!("Retransmission failed: {}", e);
+            }
+        }
+
+        pkts.len()
+    }
+
+    /**
+     * Switches between thumbnail mode (only keyframes, at most one per passed interval)
+     * and full rate (None). Either way, the track restarts from the latest keyframe.
+     */
+    pub fn set_thumbnail(&self, interval: Option<Duration>) {
+        *self.thumbnail.lock().unwrap() = interval;
+        self.controls.switch.notify_one();
+    }
+
+    /**
+     * The track currently being sent (the current rendition, for streams with several).
+     */
+    pub fn rtp_track(&self) -> Option<Arc<RtpTrack>> {
+        self.rtp_track.lock().unwrap().upgrade()
+    }
+
+    /**
+     * Switches to another rendition of the track's stream at the rendition's next keyframe.
+     * The current rendition keeps playing until then, and output continues on from it,
+     * so the client sees one continuous track.
+     */
+    pub async fn switch_rendition(self: &Arc<Self>, rendition: &str, rtp_track: &Arc<RtpTrack>) {
+        let generation = self.switches.fetch_add(1, Ordering::Relaxed) + 1;
+
+        // Nothing's playing to switch seamlessly from. Resuming starts on the new rendition.
+        if self.paused.load(Ordering::Relaxed) {
+            *self.rtp_track.lock().unwrap() = Arc::downgrade(rtp_track);
+            self.rendition_changed(rtp_track, rendition);
+            return;
+        }
+
+        BufferedTrack::task_switcher(
+            Arc::downgrade(self),
+            Arc::downgrade(rtp_track),
+            rendition.to_string(),
+            generation,
+        );
+        rtp_track.request_keyframe().await;
+    }
+
+    /**
+     * Rendition switch task. Waits for the passed rendition's next keyframe, then swaps
+     * it in and restarts the pusher from that keyframe. Exits without switching if
+     * another switch was requested in the meantime, or no keyframe arrives in time.
+     */
+    fn task_switcher(
+        buffered_track: Weak<BufferedTrack>,
+        target: Weak<RtpTrack>,
+        rendition: String,
+        generation: u64,
+    ) {
+        tokio::spawn(async move {
+            let (mut rx, track_def) = match target.upgrade() {
+                Some(t) => (t.subscribe(), t.track_def.clone()),
+                None => return,
+            };
+
+            let keyframe = async {
+                loop {
+                    match rx.recv().await {
+                        Ok(pkt) if track_def.is_keyframe(&pkt) => return true,
+                        Ok(_) | Err(RecvError::Lagged(_)) => (),
+                        Err(RecvError::Closed) => return false,
+                    }
+                }
+            };
+            if !matches!(timeout(SWITCH_TIMEOUT, keyframe).await, Ok(true)) {
+                println!("No keyframe from rendition {}. Not switching", rendition);
+                return;
+            }
+
+            let (track, target) = match (buffered_track.upgrade(), target.upgrade()) {
+                (Some(track), Some(target)) => (track, target),
+                _ => return,
+            };
+            if track.switches.load(Ordering::Relaxed) != generation {
+                return;
+            }
+
+            println!("Switching to rendition {}", rendition);
+            *track.rtp_track.lock().unwrap() = Arc::downgrade(&target);
+            track.controls.switch.notify_one();
+            track.rendition_changed(&target, &rendition);
+        });
+    }
+
+    fn rendition_changed(&self, rtp_track: &RtpTrack, rendition: &str) {
+        self.events
+            .send(ClientEvent::RenditionChanged {
+                stream_id: rtp_track.stream_def.id.clone(),
+                rendition: rendition.to_string(),
+            })
+            .ok();
+    }
+
     pub async fn kill(&self) {
         self.controls.kill.notify_one();
     }
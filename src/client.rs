@@ -1,63 +1,165 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
 };
-use tokio::sync::{watch, Mutex, RwLock};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc, watch, Mutex, Notify, RwLock};
+use tokio::time::{interval, Instant};
 use webrtc::{
-    api::{
-        interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
+    api::API,
+    data_channel::{
+        data_channel_init::RTCDataChannelInit, data_channel_message::DataChannelMessage,
+        data_channel_state::RTCDataChannelState, RTCDataChannel,
+    },
+    ice_transport::{
+        ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_connection_state::RTCIceConnectionState,
     },
-    ice_transport::{ice_connection_state::RTCIceConnectionState, ice_server::RTCIceServer},
-    interceptor::registry::Registry,
     peer_connection::{
-        configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
-        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+        configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
+        signaling_state::RTCSignalingState,
+        RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::{
+        full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+        receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+    },
+    rtcp::receiver_report::ReceiverReport,
+    rtcp::transport_feedbacks::{
+        transport_layer_cc::TransportLayerCc, transport_layer_nack::TransportLayerNack,
     },
-    rtp_transceiver::rtp_sender::RTCRtpSender,
+    rtp_transceiver::{
+        rtp_sender::RTCRtpSender, rtp_transceiver_direction::RTCRtpTransceiverDirection,
+        RTCRtpTransceiver, RTCRtpTransceiverInit,
+    },
+    stats::StatsReportType,
+    track::track_local::TrackLocal,
+};
+
+use crate::{
+    bandwidth::{BandwidthEstimator, BandwidthStats},
+    buffered_track::{BufferedTrack, LagStats},
+    client_events::{ClientCommand, ClientEvent, DATA_CHANNEL_PREFIX, EVENTS_CHANNEL},
+    client_info::{self, ClientInfo, ClientStreamInfo},
+    control::ControlLink,
+    data_track::DataTrack,
+    retransmit::{NackCounter, NackStats},
+    rewriter::SentPosition,
+    rtc_config::RtcConfig,
+    rtp_track::RtpTrack,
+    stream_manager::{Stream, MAIN_RENDITION},
+    ControlDef,
 };
+// Label of the data channel browsers open for server-initiated renegotiation.
+const SIGNALLING_CHANNEL: &str = "signalling";
+
+// Events held while a client's events channel isn't open yet.
+const MAX_PENDING_EVENTS: usize = 64;
+
+// Interval between stats pushes to clients.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Interval between bandwidth estimate updates (and stream pause/resume decisions).
+const BANDWIDTH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Fraction of the estimated bandwidth streams are allocated, leaving room for overhead and bursts.
+const BANDWIDTH_HEADROOM: f64 = 0.9;
+
+// A stream paused for lack of bandwidth is only resumed (or switched to a better rendition)
+// once there's this much more room than its bitrate, and this long after the last change,
+// so it doesn't flap.
+const RESUME_MARGIN: f64 = 1.2;
+const RESUME_HOLDOFF: Duration = Duration::from_secs(10);
 
-use crate::{buffered_track::BufferedTrack, stream_manager::Stream};
 struct TrackedStream {
     stream: Arc<Stream>,
-    sender: Arc<RTCRtpSender>,
+    video: Option<TrackedVideo>,
+    data: Option<Arc<RTCDataChannel>>, // Carries the stream's data track, if it has one
+    control: Option<Arc<ControlLink>>, // Forwards commands to the stream's control endpoint
+}
+
+struct TrackedVideo {
+    slot: Arc<Slot>,
     buffer: Arc<BufferedTrack>,
+    paused_at: Option<Instant>, // When it was paused for lack of bandwidth
+    rendition: String, // The rendition being sent, or being switched to
+    requested: Option<String>, // Picked by the bandwidth controller if the client hasn't asked for one
+    switched_at: Option<Instant>, // When the rendition last changed
+    thumbnail: bool, // Only sending keyframes. Left out of bandwidth allocation
+    viewer_paused: bool, // Paused by the client (ie a hidden tab)
+}
+
+impl TrackedVideo {
+    /**
+     * Whether the video is paused, by the client or for lack of bandwidth.
+     */
+    fn is_paused(&self) -> bool {
+        self.viewer_paused || self.paused_at.is_some()
+    }
+}
+
+/**
+ * A sendonly transceiver and the track it's currently sending, if any.
+ * Removed streams leave their transceiver in the client's pool to be reused
+ * by the next added stream (using replace_track), rather than removing it.
+ * This keeps the SDP from growing with every stream that's ever been viewed,
+ * and only requires renegotiation when the pool has to grow.
+ */
+struct Slot {
+    transceiver: Arc<RTCRtpTransceiver>,
+    sender: Arc<RTCRtpSender>,
+    buffer: Arc<RwLock<Weak<BufferedTrack>>>, // Read by the slot's RTCP reader
+    last_sent: std::sync::Mutex<Option<SentPosition>>, // Where the previous track left off
+    jitter: Arc<std::sync::Mutex<Option<u32>>>, // From the latest receiver report, in RTP time units
 }
 pub struct Client {
     streams: Arc<RwLock<HashMap<String, TrackedStream>>>,
-    peer_connection: RTCPeerConnection,
+    peer_connection: Arc<RTCPeerConnection>,
     watch_peer_status: watch::Receiver<RTCPeerConnectionState>,
     watch_failed: watch::Receiver<bool>,
-    signalling: Mutex<()>,
+    signalling: Arc<Mutex<()>>,
+    signal_channel: Arc<RwLock<Option<Arc<RTCDataChannel>>>>,
+    negotiation_pending: Arc<AtomicBool>, // Negotiation was needed before the channel opened
+    free_slots: Mutex<Vec<Arc<Slot>>>,
+    local_candidates: Arc<LocalCandidates>,
+    pending_candidates: Mutex<Vec<RTCIceCandidateInit>>,
+    nack_counter: Arc<RwLock<NackCounter>>,
+    bandwidth: Arc<RwLock<BandwidthEstimator>>,
+    lag_stats: Arc<std::sync::Mutex<LagStats>>, // Shared with the client's buffered tracks
+    events: mpsc::UnboundedSender<ClientEvent>,
+    control_token: Arc<RwLock<Option<String>>>, // Checked against streams' control tokens
+    created: Instant,
+    connected_at: Arc<std::sync::Mutex<Option<Instant>>>, // When the client first connected
+    idle_since: std::sync::Mutex<Option<Instant>>,        // When the client last had no streams
+}
+
+/**
+ * ICE candidates gathered on our side, for trickling to the remote peer.
+ * Candidates gathered before anyone subscribed are kept so they can be replayed.
+ * A None candidate signals the end of gathering.
+ */
+struct LocalCandidates {
+    gathered: RwLock<Vec<Option<RTCIceCandidateInit>>>,
+    tx: broadcast::Sender<Option<RTCIceCandidateInit>>,
 }
 
 impl Client {
     // TODO: Error handling
-    pub async fn new() -> Result<Client> {
-        // webrtc-rs boilerplate. See their examples for more info
-        let mut m = MediaEngine::default();
-        m.register_default_codecs()?;
-
-        let mut registry = Registry::new();
-        registry = register_default_interceptors(registry, &mut m)?;
-
-        let api = Arc::new(
-            APIBuilder::new()
-                .with_media_engine(m)
-                .with_interceptor_registry(registry)
-                .build(),
-        );
-
+    pub async fn new(api: &API, rtc_config: &RtcConfig) -> Result<Client> {
         let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                // urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
+            ice_servers: rtc_config.ice_servers(),
             ..Default::default()
         };
 
         // Create this client's peer connection
-        let peer_connection = api.new_peer_connection(config).await?;
+        let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
         let (watch_tx, watch_peer_status) = watch::channel(RTCPeerConnectionState::Unspecified);
 
@@ -79,45 +181,616 @@ impl Client {
                 Box::pin(async {})
             },
         ));
+        // Collect local ICE candidates as they're gathered, for trickle ICE.
+        let (candidate_tx, _) = broadcast::channel(32);
+        let local_candidates = Arc::new(LocalCandidates {
+            gathered: RwLock::new(Vec::new()),
+            tx: candidate_tx,
+        });
+
+        let candidates = Arc::downgrade(&local_candidates);
+        peer_connection.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+            let candidates = candidates.clone();
+            Box::pin(async move {
+                let init = match c {
+                    Some(c) => match c.to_json() {
+                        Ok(init) => Some(init),
+                        Err(e) => {
+                            eprintln!("Failed to serialize ICE candidate: {}", e);
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+
+                if let Some(candidates) = candidates.upgrade() {
+                    let mut gathered = candidates.gathered.write().await;
+                    gathered.push(init.clone());
+                    candidates.tx.send(init).ok();
+                }
+            })
+        }));
+
+        // Server-initiated renegotiation. Offers are sent over a signalling data channel
+        // opened by the browser; browsers that don't open one have to re-signal themselves.
+        let signalling = Arc::new(Mutex::new(()));
+        let signal_channel = Arc::new(RwLock::new(None));
+        let negotiation_pending = Arc::new(AtomicBool::new(false));
+
+        let pc = Arc::downgrade(&peer_connection);
+        let sig_lock = Arc::downgrade(&signalling);
+        let channel = Arc::downgrade(&signal_channel);
+        let pending = Arc::downgrade(&negotiation_pending);
+        peer_connection.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
+            let (pc, sig_lock, channel, pending) =
+                (pc.clone(), sig_lock.clone(), channel.clone(), pending.clone());
+            Box::pin(async move {
+                if dc.label() != SIGNALLING_CHANNEL {
+                    return;
+                }
+
+                Client::handle_signal_channel(dc.clone(), pc.clone(), sig_lock.clone());
+                match channel.upgrade() {
+                    Some(channel) => *channel.write().await = Some(dc.clone()),
+                    None => return,
+                }
+
+                // Negotiation that was needed before the channel opened was put off until now.
+                // Called right away if it's already open.
+                dc.on_open(Box::new(move || {
+                    Box::pin(async move {
+                        if !pending
+                            .upgrade()
+                            .is_some_and(|p| p.swap(false, Ordering::Relaxed))
+                        {
+                            return;
+                        }
+                        if let Err(e) = Client::renegotiate(pc, sig_lock, channel, pending).await {
+                            eprintln!("Renegotiation failed: {}", e);
+                        }
+                    })
+                }));
+            })
+        }));
+
+        let pc = Arc::downgrade(&peer_connection);
+        let sig_lock = Arc::downgrade(&signalling);
+        let channel = Arc::downgrade(&signal_channel);
+        let pending = Arc::downgrade(&negotiation_pending);
+        peer_connection.on_negotiation_needed(Box::new(move || {
+            let (pc, sig_lock, channel, pending) =
+                (pc.clone(), sig_lock.clone(), channel.clone(), pending.clone());
+            Box::pin(async move {
+                // Runs on the peer connection's operation queue, which offering needs.
+                tokio::spawn(async move {
+                    if let Err(e) = Client::renegotiate(pc, sig_lock, channel, pending).await {
+                        eprintln!("Renegotiation failed: {}", e);
+                    }
+                });
+            })
+        }));
+
+        // Events for the client. Sent over a data channel we create.
+        let events_channel = peer_connection
+            .create_data_channel(EVENTS_CHANNEL, None)
+            .await?;
+        let (events, events_rx) = mpsc::unbounded_channel();
+
         let (watch_failed_tx, watch_failed) = watch::channel(false);
 
+        // The client sends commands back over the same channel.
+        let streams = Arc::new(RwLock::new(HashMap::new()));
+        Client::handle_commands(&events_channel, Arc::downgrade(&streams), events.clone());
+        Client::task_event_sender(events_channel, events_rx, watch_failed.clone());
+
         let c = Client {
-            streams: Arc::new(RwLock::new(HashMap::new())),
+            streams,
             peer_connection,
             watch_peer_status,
             watch_failed,
-            signalling: Mutex::new(()),
+            signalling,
+            signal_channel,
+            negotiation_pending,
+            free_slots: Mutex::new(Vec::new()),
+            local_candidates,
+            pending_candidates: Mutex::new(Vec::new()),
+            nack_counter: Arc::new(RwLock::new(NackCounter::new())),
+            bandwidth: Arc::new(RwLock::new(BandwidthEstimator::new())),
+            lag_stats: Arc::new(std::sync::Mutex::new(LagStats::default())),
+            events,
+            control_token: Arc::new(RwLock::new(None)),
+            created: Instant::now(),
+            connected_at: Arc::new(std::sync::Mutex::new(None)),
+            idle_since: std::sync::Mutex::new(Some(Instant::now())),
         };
 
-        c.task_track_controller(watch_failed_tx);
+        c.task_track_controller(watch_failed_tx, rtc_config.disconnected_grace());
+        c.task_stats_pusher();
+        c.task_bandwidth_controller();
 
         Ok(c)
     }
 
+    /**
+     * Handles the commands a client sends on its events data channel.
+     */
+    fn handle_commands(
+        dc: &RTCDataChannel,
+        streams: Weak<RwLock<HashMap<String, TrackedStream>>>,
+        events: mpsc::UnboundedSender<ClientEvent>,
+    ) {
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            let (streams, events) = (streams.clone(), events.clone());
+            Box::pin(async move {
+                let streams = match streams.upgrade() {
+                    Some(s) => s,
+                    None => return,
+                };
+
+                let res = match serde_json::from_slice::<ClientCommand>(&msg.data) {
+                    Ok(ClientCommand::Pause { stream_id }) => {
+                        Client::pause_stream(&streams, &events, &stream_id, true).await
+                    }
+                    Ok(ClientCommand::Resume { stream_id }) => {
+                        Client::pause_stream(&streams, &events, &stream_id, false).await
+                    }
+                    Err(e) => Err(e.into()),
+                };
+
+                if let Err(e) = res {
+                    eprintln!("Client command failed: {}", e);
+                }
+            })
+        }));
+    }
+
+    /**
+     * Event sender task. Sends queued events over the client's events data channel.
+     * Events sent before the channel opens are held (up to a limit) until it does.
+     * Exits once the client fails or is gone. The channel's command handler holds an
+     * event sender of its own, so the queue never closes while the task holds the channel.
+     */
+    fn task_event_sender(
+        dc: Arc<RTCDataChannel>,
+        mut rx: mpsc::UnboundedReceiver<ClientEvent>,
+        mut failed: watch::Receiver<bool>,
+    ) {
+        let opened = Arc::new(Notify::new());
+        let o = opened.clone();
+        dc.on_open(Box::new(move || {
+            o.notify_one();
+            Box::pin(async {})
+        }));
+
+        tokio::spawn(async move {
+            let mut pending = VecDeque::new();
+
+            loop {
+                select! {
+                    ev = rx.recv() => match ev {
+                        Some(ev) => pending.push_back(ev),
+                        None => break,
+                    },
+                    _ = opened.notified() => (),
+                    // Either the client failed, or it was dropped along with the sender.
+                    _ = failed.changed() => break,
+                }
+
+                if dc.ready_state() != RTCDataChannelState::Open {
+                    // Clients that never open the channel (ie WHEP players) shouldn't pile up events.
+                    while pending.len() > MAX_PENDING_EVENTS {
+                        pending.pop_front();
+                    }
+                    continue;
+                }
+
+                while let Some(ev) = pending.pop_front() {
+                    let msg = match serde_json::to_string(&ev) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            eprintln!("Failed to serialize client event: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = dc.send_text(msg).await {
+                        eprintln!("Failed to send client event: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    /**
+     * Periodically pushes connection stats to the client.
+     */
+    fn task_stats_pusher(&self) {
+        let nack_counter = Arc::downgrade(&self.nack_counter);
+        let lag_stats = Arc::downgrade(&self.lag_stats);
+        let bandwidth = Arc::downgrade(&self.bandwidth);
+        let events = self.events.clone();
+
+        tokio::spawn(async move {
+            let mut interval = interval(STATS_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let nack = match nack_counter.upgrade() {
+                    Some(c) => c.write().await.stats(Instant::now()),
+                    None => break,
+                };
+                let lag = match lag_stats.upgrade() {
+                    Some(l) => *l.lock().unwrap(),
+                    None => break,
+                };
+                let bandwidth = match bandwidth.upgrade() {
+                    Some(b) => b.read().await.stats(),
+                    None => break,
+                };
+
+                let stats = ClientEvent::Stats {
+                    nack,
+                    lag,
+                    bandwidth,
+                };
+                if events.send(stats).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /**
+     * Periodically updates the client's bandwidth estimate, and pauses its lowest priority
+     * streams while it can't keep up with all of them. Paused streams are resumed
+     * (highest priority first) once there's room for them again.
+     */
+    fn task_bandwidth_controller(&self) {
+        let pc = Arc::downgrade(&self.peer_connection);
+        let streams = Arc::downgrade(&self.streams);
+        let bandwidth = Arc::downgrade(&self.bandwidth);
+        let events = self.events.clone();
+
+        tokio::spawn(async move {
+            let mut interval = interval(BANDWIDTH_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let (pc, streams, bandwidth) =
+                    match (pc.upgrade(), streams.upgrade(), bandwidth.upgrade()) {
+                        (Some(pc), Some(s), Some(b)) => (pc, s, b),
+                        _ => break,
+                    };
+
+                if pc.connection_state() != RTCPeerConnectionState::Connected {
+                    continue;
+                }
+
+                let (bytes_sent, rr_loss) = Client::send_totals(&pc).await;
+                let estimate = bandwidth
+                    .write()
+                    .await
+                    .update(bytes_sent, rr_loss, Instant::now())
+                    .estimate_bps();
+
+                let bitrates = Client::rendition_bitrates(&streams).await;
+                let mut streams = streams.write().await;
+                Client::allocate_bandwidth(&mut streams, &bitrates, estimate, &events).await;
+            }
+        });
+    }
+
+    /**
+     * Total bytes sent on the passed connection's tracks, and the highest
+     * fraction lost from the latest receiver reports about them.
+     */
+    async fn send_totals(pc: &RTCPeerConnection) -> (u64, f64) {
+        let mut bytes_sent = 0;
+        let mut loss: f64 = 0.0;
+        for report in pc.get_stats().await.reports.values() {
+            match report {
+                StatsReportType::OutboundRTP(s) => bytes_sent += s.bytes_sent + s.header_bytes_sent,
+                StatsReportType::RemoteInboundRTP(s) => loss = loss.max(s.fraction_lost),
+                _ => (),
+            }
+        }
+        (bytes_sent, loss)
+    }
+
+    /**
+     * Current bitrates of the renditions of a client's streams, by stream and rendition ID.
+     * Measured without holding the streams lock, since each means awaiting the track's status.
+     */
+    async fn rendition_bitrates(
+        streams: &RwLock<HashMap<String, TrackedStream>>,
+    ) -> HashMap<String, HashMap<String, f64>> {
+        let tracks: Vec<_> = streams
+            .read()
+            .await
+            .iter()
+            .map(|(id, t)| (id.clone(), t.stream.renditions.clone()))
+            .collect();
+
+        let mut bitrates = HashMap::new();
+        for (id, renditions) in tracks {
+            let mut stream_bitrates = HashMap::new();
+            for (rendition, track) in renditions {
+                stream_bitrates.insert(rendition, track.status().await.bytes_per_sec() * 8.0);
+            }
+            bitrates.insert(id, stream_bitrates);
+        }
+        bitrates
+    }
+
+    /**
+     * Decides what of a client's streams fit in its estimated bandwidth, in priority order.
+     * Streams the client hasn't picked a rendition for are stepped down to lower renditions
+     * before being paused. The highest priority stream is never paused, and falls back to
+     * its lowest rendition instead.
+     */
+    async fn allocate_bandwidth(
+        streams: &mut HashMap<String, TrackedStream>,
+        bitrates: &HashMap<String, HashMap<String, f64>>,
+        estimate_bps: f64,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+    ) {
+        let now = Instant::now();
+
+        let mut ranked = Vec::new();
+        for (id, t) in streams.iter_mut() {
+            let priority = t.stream.def.priority;
+            let video = match t.video {
+                Some(ref mut v) if !v.thumbnail && !v.viewer_paused => v,
+                _ => continue,
+            };
+
+            // Renditions the stream may be sent at, best first, with their bitrates.
+            // Streams added since the bitrates were measured wait for the next round.
+            let mut options = Vec::new();
+            for (rendition, track) in t.stream.renditions.iter() {
+                let bitrate_bps = bitrates.get(id).and_then(|b| b.get(rendition));
+                if let Some(&bitrate_bps) = bitrate_bps {
+                    if video.requested.as_ref().is_none_or(|r| r == rendition) {
+                        options.push((rendition, track, bitrate_bps));
+                    }
+                }
+            }
+            if options.is_empty() {
+                continue;
+            }
+            ranked.push((priority, id, video, options));
+        }
+        ranked.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+
+        let budget = estimate_bps * BANDWIDTH_HEADROOM;
+        let mut used = 0.0;
+        for (i, (_, id, video, options)) in ranked.into_iter().enumerate() {
+            let current = options.iter().position(|(r, _, _)| **r == video.rendition);
+            let settled = video
+                .paused_at
+                .or(video.switched_at)
+                .is_none_or(|at| now.duration_since(at) >= RESUME_HOLDOFF);
+
+            // The best rendition that fits. Moving up needs extra room.
+            let choice = options.iter().enumerate().position(|(idx, (_, _, bitrate_bps))| {
+                let upgrade = video.paused_at.is_some() || current.is_none_or(|c| idx < c);
+                match upgrade {
+                    true => settled && used + bitrate_bps * RESUME_MARGIN <= budget,
+                    false => used + bitrate_bps <= budget,
+                }
+            });
+            let choice = match choice {
+                None if i == 0 => Some(options.len() - 1),
+                c => c,
+            };
+
+            match choice {
+                Some(idx) => {
+                    let (rendition, track, bitrate_bps) = options[idx];
+                    used += bitrate_bps;
+
+                    if Some(idx) != current {
+                        println!("Bandwidth: switching {} to {}", id, rendition);
+                        Client::switch_rendition(video, rendition, track).await;
+                    }
+
+                    if video.paused_at.take().is_some() {
+                        println!("Bandwidth: resuming {}", id);
+                        video.buffer.resume().await;
+                        events
+                            .send(ClientEvent::StreamResumed {
+                                stream_id: id.clone(),
+                            })
+                            .ok();
+                    }
+                }
+                None if video.paused_at.is_none() => {
+                    let (_, _, bitrate_bps) = options[current.unwrap_or(0)];
+                    println!(
+                        "Bandwidth: pausing {} ({:.0} kbps, {:.0} kbps estimated)",
+                        id,
+                        bitrate_bps / 1000.0,
+                        estimate_bps / 1000.0
+                    );
+                    video.paused_at = Some(now);
+                    video.buffer.stop().await;
+                    events
+                        .send(ClientEvent::StreamPaused {
+                            stream_id: id.clone(),
+                            reason: "bandwidth".to_string(),
+                        })
+                        .ok();
+                }
+                None => (),
+            }
+        }
+    }
+
+    /**
+     * Switches a stream's video to the passed rendition, at the rendition's next keyframe.
+     */
+    async fn switch_rendition(
+        video: &mut TrackedVideo,
+        rendition: &str,
+        rtp_track: &Arc<RtpTrack>,
+    ) {
+        if video.rendition == rendition {
+            return;
+        }
+        video.rendition = rendition.to_string();
+        video.switched_at = Some(Instant::now());
+        video.buffer.switch_rendition(rendition, rtp_track).await;
+    }
+
+    /**
+     * Pauses (or resumes) sending a stream's video to the client, ie while it's hidden.
+     * The transceiver is kept, so this doesn't renegotiate, and resuming starts from the
+     * latest keyframe. Streams also paused for lack of bandwidth stay paused until there's room.
+     */
+    pub async fn set_stream_paused(&self, stream_id: &str, paused: bool) -> Result<()> {
+        Client::pause_stream(&self.streams, &self.events, stream_id, paused).await
+    }
+
+    async fn pause_stream(
+        streams: &RwLock<HashMap<String, TrackedStream>>,
+        events: &mpsc::UnboundedSender<ClientEvent>,
+        stream_id: &str,
+        paused: bool,
+    ) -> Result<()> {
+        let mut streams = streams.write().await;
+        let video = streams
+            .get_mut(stream_id)
+            .and_then(|t| t.video.as_mut())
+            .ok_or(anyhow!("Client isn't viewing video of {}", stream_id))?;
+
+        if video.viewer_paused == paused {
+            return Ok(());
+        }
+        video.viewer_paused = paused;
+
+        // Already stopped for lack of bandwidth. The bandwidth controller resumes it.
+        if video.paused_at.is_some() {
+            return Ok(());
+        }
+
+        let event = if paused {
+            video.buffer.stop().await;
+            ClientEvent::StreamPaused {
+                stream_id: stream_id.to_string(),
+                reason: "viewer".to_string(),
+            }
+        } else {
+            video.buffer.resume().await;
+            ClientEvent::StreamResumed {
+                stream_id: stream_id.to_string(),
+            }
+        };
+        events.send(event).ok();
+        Ok(())
+    }
+
+    /**
+     * Switches a stream's video between thumbnail mode (only keyframes, at most one per
+     * passed interval) and full rate (None). Thumbnails are cheap, so they're not paused
+     * for lack of bandwidth; a paused stream switched to thumbnail mode is resumed.
+     */
+    pub async fn set_thumbnail(&self, stream_id: &str, interval: Option<Duration>) -> Result<()> {
+        let mut streams = self.streams.write().await;
+        let video = streams
+            .get_mut(stream_id)
+            .and_then(|t| t.video.as_mut())
+            .ok_or(anyhow!("Client isn't viewing video of {}", stream_id))?;
+
+        video.thumbnail = interval.is_some();
+        video.buffer.set_thumbnail(interval);
+
+        if video.thumbnail && video.paused_at.take().is_some() && !video.viewer_paused {
+            video.buffer.resume().await;
+            self.send_event(ClientEvent::StreamResumed {
+                stream_id: stream_id.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /**
+     * Sets the rendition of a stream's video the client is sent.
+     * None leaves it to the bandwidth controller, which sends the best one that fits.
+     */
+    pub async fn set_rendition(&self, stream_id: &str, rendition: Option<String>) -> Result<()> {
+        let mut streams = self.streams.write().await;
+        let t = streams
+            .get_mut(stream_id)
+            .ok_or(anyhow!("Client isn't viewing {}", stream_id))?;
+        let video = t
+            .video
+            .as_mut()
+            .ok_or(anyhow!("{} has no video", stream_id))?;
+
+        if let Some(ref r) = rendition {
+            let rtp_track = t
+                .stream
+                .rendition(r)
+                .ok_or(anyhow!("{} has no rendition {}", stream_id, r))?;
+            Client::switch_rendition(video, r, rtp_track).await;
+        }
+        video.requested = rendition;
+        Ok(())
+    }
+
+    /**
+     * Queues an event for sending to the client.
+     */
+    pub fn send_event(&self, event: ClientEvent) {
+        // An error means the event sender is gone, which only happens while closing.
+        self.events.send(event).ok();
+    }
+
     // Task for asynchronously controller internal track buffers
     // based on connection state
-    pub fn task_track_controller(&self, watch_failed: watch::Sender<bool>) {
+    pub fn task_track_controller(&self, watch_failed: watch::Sender<bool>, grace: Duration) {
         let mut ps = self.watch_peer_status.clone();
         let streams = Arc::downgrade(&self.streams);
+        let connected_at = self.connected_at.clone();
 
         tokio::spawn(async move {
+            let mut disconnected_at: Option<Instant> = None;
             loop {
-                ps.changed().await?;
+                match disconnected_at {
+                    Some(at) => select! {
+                        changed = ps.changed() => changed?,
+                        _ = tokio::time::sleep_until(at + grace) => {
+                            println!("Disconnected for {:?} - cleaning up.", grace);
+                            watch_failed.send(true).ok();
+                            break;
+                        }
+                    },
+                    None => ps.changed().await?,
+                }
                 let state = ps.borrow().clone();
 
                 print!("CONNECTION STATE CHANGE: {:?}", state);
                 match state {
                     RTCPeerConnectionState::Connected => {
+                        disconnected_at = None;
+                        connected_at.lock().unwrap().get_or_insert_with(Instant::now);
                         let streams_arc = streams
                             .upgrade()
                             .ok_or(anyhow::Error::msg("Error upgrading streams"))?;
                         let streams_lock = streams_arc.read().await;
                         println!(" - resuming {} streams", streams_lock.len());
-                        for tracked_stream in streams_lock.values() {
-                            tracked_stream.buffer.resync().await;
+                        let videos = streams_lock.values().filter_map(|t| t.video.as_ref());
+                        for video in videos.filter(|v| !v.is_paused()) {
+                            video.buffer.resync().await;
                         }
                     }
+                    // Possibly temporary (ie a Wi-Fi handover). The client may
+                    // recover by itself, or restart ICE with a new offer.
                     RTCPeerConnectionState::Disconnected => {
+                        println!(" - waiting up to {:?} for it to recover.", grace);
+                        disconnected_at.get_or_insert_with(Instant::now);
+                    }
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
                         println!(" - cleaning up.");
                         watch_failed.send(true).ok();
                         break;
@@ -129,13 +802,155 @@ impl Client {
         });
     }
 
-    pub async fn signal(&self, offer: RTCSessionDescription) -> Result<RTCSessionDescription> {
+    /**
+     * Sends a new offer to the client over its signalling channel.
+     * Called when tracks are added or removed on our side. If the channel isn't open
+     * (yet), negotiation is marked pending, and the offer is sent once it opens.
+     */
+    async fn renegotiate(
+        pc: Weak<RTCPeerConnection>,
+        sig_lock: Weak<Mutex<()>>,
+        channel: Weak<RwLock<Option<Arc<RTCDataChannel>>>>,
+        pending: Weak<AtomicBool>,
+    ) -> Result<()> {
+        let (pc, sig_lock, channel, pending) =
+            match (pc.upgrade(), sig_lock.upgrade(), channel.upgrade(), pending.upgrade()) {
+                (Some(pc), Some(l), Some(c), Some(p)) => (pc, l, c, p),
+                _ => return Ok(()),
+            };
+
+        let dc = {
+            // Held while marking negotiation pending, so the channel can't be
+            // set (and its on_open called) in between.
+            let dc = channel.read().await;
+            match dc.as_ref() {
+                Some(dc) if dc.ready_state() == RTCDataChannelState::Open => dc.clone(),
+                dc => {
+                    pending.store(true, Ordering::Relaxed);
+
+                    // It may have opened since it was checked, without seeing the flag.
+                    match dc {
+                        Some(dc)
+                            if dc.ready_state() == RTCDataChannelState::Open
+                                && pending.swap(false, Ordering::Relaxed) =>
+                        {
+                            dc.clone()
+                        }
+                        _ => return Ok(()),
+                    }
+                }
+            }
+        };
+
+        let _sig_lock = sig_lock.lock().await;
+
+        // Mid-negotiation. Negotiation-needed fires again once it's done, if still needed.
+        if pc.signaling_state() != RTCSignalingState::Stable {
+            return Ok(());
+        }
+
+        println!("Renegotiating");
+        let offer = pc.create_offer(None).await?;
+        pc.set_local_description(offer).await?;
+
+        let offer = pc
+            .local_description()
+            .await
+            .ok_or(anyhow::Error::msg("local description generation failed"))?;
+        dc.send_text(serde_json::to_string(&offer)?).await?;
+
+        Ok(())
+    }
+
+    /**
+     * Handles messages on a client's signalling channel.
+     * The only thing the client sends over it is answers to our offers.
+     */
+    fn handle_signal_channel(
+        dc: Arc<RTCDataChannel>,
+        pc: Weak<RTCPeerConnection>,
+        sig_lock: Weak<Mutex<()>>,
+    ) {
+        dc.on_message(Box::new(move |msg: DataChannelMessage| {
+            let (pc, sig_lock) = (pc.clone(), sig_lock.clone());
+            Box::pin(async move {
+                let (pc, sig_lock) = match (pc.upgrade(), sig_lock.upgrade()) {
+                    (Some(pc), Some(l)) => (pc, l),
+                    _ => return,
+                };
+
+                let answer: RTCSessionDescription = match serde_json::from_slice(&msg.data) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        eprintln!("Bad signalling message: {}", e);
+                        return;
+                    }
+                };
+
+                let _sig_lock = sig_lock.lock().await;
+                if answer.sdp_type != RTCSdpType::Answer
+                    || pc.signaling_state() != RTCSignalingState::HaveLocalOffer
+                {
+                    eprintln!(
+                        "Ignoring unexpected {} on signalling channel",
+                        answer.sdp_type
+                    );
+                    return;
+                }
+
+                if let Err(e) = pc.set_remote_description(answer).await {
+                    eprintln!("Failed to apply renegotiation answer: {}", e);
+                }
+            })
+        }));
+    }
+
+    /**
+     * Answers the passed offer.
+     * With trickle ICE, the answer is returned immediately and candidates are exchanged
+     * through add_ice_candidate/subscribe_ice_candidates. Without it, the answer is
+     * held back until gathering completes so it contains every candidate.
+     *
+     * Returns None if the offer collided with one of ours that's still awaiting an answer.
+     * We never back down (the browser does), so the client should drop its offer and
+     * answer ours instead.
+     */
+    pub async fn signal(
+        &self,
+        offer: RTCSessionDescription,
+        trickle: bool,
+    ) -> Result<Option<RTCSessionDescription>> {
         // Holding this mutex will prevent multiple signals from happening simultaneously
         let _sig_lock = self.signalling.lock().await;
 
+        if self.peer_connection.signaling_state() == RTCSignalingState::HaveLocalOffer {
+            println!("Signalling collision, keeping our offer");
+            return Ok(None);
+        }
+
+        // An offer with new ICE credentials restarts ICE (ie after a network change).
+        // webrtc-rs restarts its side when the offer is applied, gathering new candidates,
+        // so the previous generation's candidates shouldn't be replayed to trickle subscribers.
+        let ufrag = ice_ufrag(&offer.sdp).map(str::to_string);
+        if let Some(current) = self.peer_connection.remote_description().await {
+            if ice_ufrag(&current.sdp) != ufrag.as_deref() {
+                println!("ICE restart");
+                self.local_candidates.gathered.write().await.clear();
+            }
+        }
+
         // Set the remote SessionDescription
         self.peer_connection.set_remote_description(offer).await?;
 
+        // Apply any candidates that were trickled in before the offer,
+        // except those left over from a previous ICE generation.
+        for candidate in self.pending_candidates.lock().await.drain(..) {
+            if matches!((&candidate.username_fragment, &ufrag), (Some(c), Some(u)) if c != u) {
+                continue;
+            }
+            self.peer_connection.add_ice_candidate(candidate).await?;
+        }
+
         // Create channel that is blocked until ICE Gathering is complete
         let mut gather_complete = self.peer_connection.gathering_complete_promise().await;
 
@@ -145,13 +960,11 @@ impl Client {
         // Sets the LocalDescription, and starts our UDP listeners
         self.peer_connection.set_local_description(answer).await?;
 
-        // Block until ICE Gathering is complete, disabling trickle ICE
-        // we do this because we only can exchange one signaling message
-        // in a production application you should exchange ICE Candidates via OnICECandidate
-
-        // ^^ Comment from webrtc-rs example. How you're actually supposed to ICE gather IDK
-        // atm. So this only works on local networks for now.
-        let _ = gather_complete.recv().await;
+        // Non-trickling peers can only exchange one signalling message,
+        // so the answer has to contain all of our candidates.
+        if !trickle {
+            let _ = gather_complete.recv().await;
+        }
 
         let r = self
             .peer_connection
@@ -159,76 +972,570 @@ impl Client {
             .await
             .ok_or(anyhow::Error::msg("local description generation failed"))?;
 
-        Ok(r)
+        Ok(Some(r))
+    }
+
+    /**
+     * Adds a remote ICE candidate trickled in by the peer.
+     * Candidates can race ahead of the offer they belong to (the first offer,
+     * or an ICE restart's), in which case they're held until the offer is signalled.
+     */
+    pub async fn add_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        let _sig_lock = self.signalling.lock().await;
+
+        let ahead_of_offer = match self.peer_connection.remote_description().await {
+            Some(remote) => matches!(
+                (ice_ufrag(&remote.sdp), candidate.username_fragment.as_deref()),
+                (Some(r), Some(c)) if r != c
+            ),
+            None => true,
+        };
+
+        if ahead_of_offer {
+            self.pending_candidates.lock().await.push(candidate);
+        } else {
+            self.peer_connection.add_ice_candidate(candidate).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Returns the local ICE candidates gathered so far, and a receiver for
+     * candidates gathered from here on. A None candidate means gathering is complete.
+     */
+    pub async fn subscribe_ice_candidates(
+        &self,
+    ) -> (
+        Vec<Option<RTCIceCandidateInit>>,
+        broadcast::Receiver<Option<RTCIceCandidateInit>>,
+    ) {
+        // Hold the lock while subscribing so no candidate is missed or duplicated.
+        let gathered = self.local_candidates.gathered.read().await;
+        (gathered.clone(), self.local_candidates.tx.subscribe())
     }
 
     /**
      * Connects this client with the passed stream.
-     * Both the video and audio tracks, if applicable, are added.
+     * The video track, data track and control channel, if applicable, are added.
      */
     pub async fn add_stream(&self, stream: Arc<Stream>) {
         println!("Adding stream");
 
-        if let Some(ref rtp_track) = stream.video {
-            println!("Creating video track");
+        let video = match stream.video {
+            Some(ref rtp_track) => {
+                println!("Creating video track");
 
-            let buffered_track = BufferedTrack::new(rtp_track.clone());
+                let buffered_track = BufferedTrack::new(
+                    rtp_track.clone(),
+                    self.events.clone(),
+                    self.lag_stats.clone(),
+                );
 
-            let rtp_sender = self
-                .peer_connection
-                .add_track(buffered_track.rtc_track.clone())
-                .await
-                .expect("Failed to add track");
+                match self.attach(&buffered_track).await {
+                    Ok(slot) => Some(TrackedVideo {
+                        slot,
+                        buffer: buffered_track,
+                        paused_at: None,
+                        rendition: MAIN_RENDITION.to_string(),
+                        requested: None,
+                        switched_at: None,
+                        thumbnail: false,
+                        viewer_paused: false,
+                    }),
+                    Err(e) => {
+                        eprintln!("Failed to add track: {}", e);
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
 
-            let i_sender = rtp_sender.clone();
+        let data = match stream.data {
+            Some(ref data_track) => {
+                println!("Creating data channel");
 
-            // Read RTCP packets. Can't do anything with them ATM.
-            tokio::spawn(async move {
-                let mut rtcp_buf = vec![0u8; 1500];
-                while i_sender.read(&mut rtcp_buf).await.is_ok() {}
-            });
+                match self.open_data_channel(&stream.def.id, data_track).await {
+                    Ok(dc) => Some(dc),
+                    Err(e) => {
+                        eprintln!("Failed to add data channel: {}", e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let control = match stream.def.control {
+            Some(ref def) => match self.open_control_link(&stream.def.id, def).await {
+                Ok(link) => Some(link),
+                Err(e) => {
+                    eprintln!("Failed to add control channel: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // Add tracked stream
+        let t = TrackedStream {
+            stream: stream.clone(),
+            video,
+            data,
+            control,
+        };
+
+        let mut s = self.streams.write().await;
+
+        s.insert(stream.def.id.clone(), t);
+        *self.idle_since.lock().unwrap() = None;
+    }
+
+    /**
+     * Opens a data channel carrying the passed data track, labelled "data:<stream id>".
+     * Telemetry is only useful while it's fresh, so the channel is unordered and
+     * never retransmits.
+     */
+    async fn open_data_channel(
+        &self,
+        stream_id: &str,
+        data_track: &Arc<DataTrack>,
+    ) -> Result<Arc<RTCDataChannel>> {
+        let dc = self
+            .peer_connection
+            .create_data_channel(
+                &format!("{}{}", DATA_CHANNEL_PREFIX, stream_id),
+                Some(RTCDataChannelInit {
+                    ordered: Some(false),
+                    max_retransmits: Some(0),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
+        let weak_dc = Arc::downgrade(&dc);
+        let weak_track = Arc::downgrade(data_track);
+        dc.on_open(Box::new(move || {
+            Client::task_data_forwarder(weak_dc.clone(), weak_track.clone());
+            Box::pin(async {})
+        }));
 
-            // Add tracked stream
-            let t = TrackedStream {
-                buffer: buffered_track.clone(),
-                sender: rtp_sender.clone(),
-                stream: stream.clone(),
+        Ok(dc)
+    }
+
+    /**
+     * Opens a control channel for the passed stream. Commands the client sends on it are
+     * checked against its token and rate limit before being forwarded to the endpoint.
+     * Rejected commands are reported as events.
+     */
+    async fn open_control_link(
+        &self,
+        stream_id: &str,
+        def: &ControlDef,
+    ) -> Result<Arc<ControlLink>> {
+        let link = ControlLink::open(&self.peer_connection, stream_id, def).await?;
+
+        let weak_link = Arc::downgrade(&link);
+        let token = Arc::downgrade(&self.control_token);
+        let events = self.events.clone();
+        link.channel().on_message(Box::new(move |msg: DataChannelMessage| {
+            let (link, token, events) = (weak_link.clone(), token.clone(), events.clone());
+            Box::pin(async move {
+                let (link, token) = match (link.upgrade(), token.upgrade()) {
+                    (Some(l), Some(t)) => (l, t),
+                    _ => return,
+                };
+
+                let reason = if !link.permits(token.read().await.as_deref()) {
+                    Some("forbidden".to_string())
+                } else if !link.allow() {
+                    Some("rate_limited".to_string())
+                } else {
+                    link.forward(&msg).await.err().map(|e| e.to_string())
+                };
+
+                if let Some(reason) = reason {
+                    events
+                        .send(ClientEvent::ControlRejected {
+                            stream_id: link.stream_id.clone(),
+                            reason,
+                        })
+                        .ok();
+                }
+            })
+        }));
+
+        Ok(link)
+    }
+
+    /**
+     * Sets the token this client's commands are authorized with.
+     */
+    pub async fn set_control_token(&self, token: Option<String>) {
+        *self.control_token.write().await = token;
+    }
+
+    /**
+     * Forwards a data track's messages over an open data channel, starting with
+     * the last received message if the track replays it.
+     * Exits once the channel closes or the track is deleted.
+     */
+    fn task_data_forwarder(dc: Weak<RTCDataChannel>, data_track: Weak<DataTrack>) {
+        tokio::spawn(async move {
+            let (mut rx, last) = match data_track.upgrade() {
+                Some(t) => (t.subscribe(), t.last().await),
+                None => return,
             };
 
-            let mut s = self.streams.write().await;
+            let mut next = last;
+            loop {
+                if let Some(msg) = next.take() {
+                    match dc.upgrade() {
+                        Some(dc) if dc.ready_state() == RTCDataChannelState::Open => {
+                            // An unreliable channel; messages that can't be sent are dropped.
+                            dc.send(&msg).await.ok();
+                        }
+                        _ => break,
+                    }
+                }
 
-            s.insert(stream.def.id.clone(), t);
+                next = match rx.recv().await {
+                    Ok(msg) => Some(msg),
+                    Err(broadcast::error::RecvError::Lagged(_)) => None,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+            }
+            println!("Data forwarder exited.");
+        });
+    }
+
+    /**
+     * Attaches the passed track to a free transceiver, or a new one if the pool is empty.
+     * Only new transceivers require renegotiation.
+     */
+    async fn attach(&self, buffered_track: &Arc<BufferedTrack>) -> Result<Arc<Slot>> {
+        let track: Arc<dyn TrackLocal + Send + Sync> = buffered_track.rtc_track.clone();
 
-            //dbg!(s.keys());
+        let mut free = self.free_slots.lock().await;
+        for i in (0..free.len()).rev() {
+            let slot = free[i].clone();
+            if slot.transceiver.kind() != track.kind() {
+                continue;
+            }
+
+            // Fails if the negotiated codecs don't fit the new track.
+            // The transceiver stays in the pool in that case, for a track it does fit.
+            if let Err(e) = slot.sender.replace_track(Some(track.clone())).await {
+                eprintln!("Couldn't reuse transceiver: {}", e);
+                continue;
+            }
+            free.remove(i);
+
+            if let Some(last) = *slot.last_sent.lock().unwrap() {
+                buffered_track.continue_after(last);
+            }
+            *slot.buffer.write().await = Arc::downgrade(buffered_track);
+
+            // Already negotiated, so there won't be a new track event on the client
+            // to start the stream. Start it right away instead.
+            if self.peer_connection.connection_state() == RTCPeerConnectionState::Connected {
+                buffered_track.resync().await;
+            }
+            return Ok(slot);
         }
+        drop(free);
+
+        let transceiver = self
+            .peer_connection
+            .add_transceiver_from_track(
+                track,
+                &[RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Sendonly,
+                    send_encodings: Vec::new(),
+                }],
+            )
+            .await?;
+
+        let sender = transceiver
+            .sender()
+            .await
+            .ok_or(anyhow::Error::msg("Transceiver has no sender"))?;
+
+        let slot = Arc::new(Slot {
+            transceiver,
+            sender: sender.clone(),
+            buffer: Arc::new(RwLock::new(Arc::downgrade(buffered_track))),
+            last_sent: std::sync::Mutex::new(None),
+            jitter: Arc::new(std::sync::Mutex::new(None)),
+        });
+
+        Client::task_rtcp_reader(
+            sender,
+            Arc::downgrade(&slot.buffer),
+            Arc::downgrade(&slot.jitter),
+            Arc::downgrade(&self.nack_counter),
+            Arc::downgrade(&self.bandwidth),
+        );
+
+        Ok(slot)
     }
 
     /**
-     * Links the track with the passed index to the passed RTP stream.
-     * Switches with fast-forwarding, allowing seamless switches.
+     * RTCP reader task. Handles NACKs and keyframe requests (PLI/FIR) for
+     * whichever track a slot is currently sending, and records the slot's jitter.
+     * Exits once the sender or slot is closed (connection closed).
+     */
+    fn task_rtcp_reader(
+        sender: Arc<RTCRtpSender>,
+        slot_buffer: Weak<RwLock<Weak<BufferedTrack>>>,
+        slot_jitter: Weak<std::sync::Mutex<Option<u32>>>,
+        nack_counter: Weak<RwLock<NackCounter>>,
+        bandwidth: Weak<RwLock<BandwidthEstimator>>,
+    ) {
+        tokio::spawn(async move {
+            // Receiver reports cover all of the client's tracks. Only this slot's is of interest.
+            let ssrc = sender.get_parameters().await.encodings.first().map(|e| e.ssrc);
+
+            while let Ok((pkts, _)) = sender.read_rtcp().await {
+                // Congestion feedback is about the whole connection, whatever the slot is sending.
+                match bandwidth.upgrade() {
+                    Some(b) => {
+                        let mut b = b.write().await;
+                        for p in pkts.iter().map(|p| p.as_any()) {
+                            if let Some(remb) = p.downcast_ref::<ReceiverEstimatedMaximumBitrate>() {
+                                b.record_remb(remb, Instant::now());
+                            } else if let Some(cc) = p.downcast_ref::<TransportLayerCc>() {
+                                b.record_twcc(cc);
+                            }
+                        }
+                    }
+                    None => break,
+                }
+
+                let report = pkts
+                    .iter()
+                    .filter_map(|p| p.as_any().downcast_ref::<ReceiverReport>())
+                    .flat_map(|rr| rr.reports.iter())
+                    .find(|r| Some(r.ssrc) == ssrc);
+                if let (Some(report), Some(jitter)) = (report, slot_jitter.upgrade()) {
+                    *jitter.lock().unwrap() = Some(report.jitter);
+                }
+
+                let buffer = match slot_buffer.upgrade() {
+                    Some(b) => b.read().await.clone(),
+                    None => break,
+                };
+
+                // Free slot. Nothing to retransmit or replay.
+                let buffer = match buffer.upgrade() {
+                    Some(b) => b,
+                    None => continue,
+                };
+
+                // Answer NACKs from the track's retransmission cache
+                for nack in pkts
+                    .iter()
+                    .filter_map(|p| p.as_any().downcast_ref::<TransportLayerNack>())
+                {
+                    let seqs: Vec<u16> = nack.nacks.iter().flat_map(|n| n.packet_list()).collect();
+
+                    let resent = buffer.retransmit(&seqs).await;
+
+                    match nack_counter.upgrade() {
+                        Some(c) => c.write().await.record(seqs.len(), resent, Instant::now()),
+                        None => return,
+                    }
+                }
+
+                let wants_keyframe = pkts.iter().any(|p| {
+                    let p = p.as_any();
+                    p.is::<PictureLossIndication>() || p.is::<FullIntraRequest>()
+                });
+
+                if wants_keyframe {
+                    buffer.request_keyframe().await;
+                }
+            }
+        });
+    }
+
+    /**
+     * Disconnects the passed stream from this client.
+     * Its transceiver goes back into the pool (sending nothing) for the next added stream,
+     * and its data and control channels, if any, are closed.
      */
     pub async fn remove_stream(&self, stream: Arc<Stream>) -> Result<()> {
         let mut s = self.streams.write().await;
         let tracked_stream = s
-            .get(&stream.def.id)
+            .remove(&stream.def.id)
             .ok_or(anyhow::Error::msg("Couldn't find stream to remove"))?;
 
-        self.peer_connection
-            .remove_track(&tracked_stream.sender)
-            .await?;
+        if s.is_empty() {
+            *self.idle_since.lock().unwrap() = Some(Instant::now());
+        }
+
+        if let Some(video) = tracked_stream.video {
+            let slot = video.slot;
+            slot.sender.replace_track(None).await?;
+            *slot.buffer.write().await = Weak::new();
+            *slot.last_sent.lock().unwrap() = video.buffer.last_sent();
+
+            self.free_slots.lock().await.push(slot);
+        }
 
-        s.remove(&stream.def.id);
+        if let Some(dc) = tracked_stream.data {
+            dc.close().await?;
+        }
+
+        if let Some(link) = tracked_stream.control {
+            link.close().await?;
+        }
+
+        self.send_event(ClientEvent::StreamRemoved {
+            stream_id: stream.def.id.clone(),
+        });
         Ok(())
     }
 
+    /**
+     * Maps the media IDs (mid) of this client's transceivers to the streams they're sending.
+     * Reused transceivers keep the msid of the first stream they sent, so browsers
+     * have to use this to tell which stream a track is showing.
+     */
+    pub async fn track_mids(&self) -> HashMap<String, String> {
+        let mut mids = HashMap::new();
+        for (id, t) in self.streams.read().await.iter() {
+            let video = match t.video {
+                Some(ref v) => v,
+                None => continue,
+            };
+            let mid = video.slot.transceiver.mid().await;
+            if !mid.is_empty() {
+                mids.insert(mid, id.clone());
+            }
+        }
+        mids
+    }
+
+    /**
+     * Details about the client's connection and streams, for the API.
+     */
+    pub async fn info(&self, uid: &str) -> ClientInfo {
+        let now = Instant::now();
+        let stats = self.peer_connection.get_stats().await;
+        let candidate_pair = client_info::candidate_pair(&stats);
+        let mut track_stats = client_info::track_stats(&stats);
+
+        let mut streams = Vec::new();
+        for (id, t) in self.streams.read().await.iter() {
+            let mut info = ClientStreamInfo {
+                stream_id: id.clone(),
+                mid: None,
+                rendition: None,
+                thumbnail: false,
+                paused: None,
+                data_channel: t.data.is_some(),
+                control_channel: t.control.is_some(),
+                video: None,
+            };
+
+            if let Some(ref v) = t.video {
+                let mid = v.slot.transceiver.mid().await;
+                info.mid = Some(mid).filter(|m| !m.is_empty());
+                info.rendition = Some(v.rendition.clone());
+                info.thumbnail = v.thumbnail;
+                info.paused = match (v.viewer_paused, v.paused_at) {
+                    (true, _) => Some("viewer".to_string()),
+                    (false, Some(_)) => Some("bandwidth".to_string()),
+                    (false, None) => None,
+                };
+
+                let ssrc = v.slot.sender.get_parameters().await.encodings.first().map(|e| e.ssrc);
+                info.video = ssrc.and_then(|ssrc| track_stats.remove(&ssrc)).map(|mut s| {
+                    let jitter = *v.slot.jitter.lock().unwrap();
+                    let clock_rate = v.buffer.rtp_track().map(|t| t.track_def.clock_rate());
+                    s.jitter_ms = jitter
+                        .zip(clock_rate)
+                        .map(|(j, rate)| j as f64 * 1000.0 / rate as f64);
+                    s
+                });
+            }
+            streams.push(info);
+        }
+        streams.sort_by(|a, b| a.stream_id.cmp(&b.stream_id));
+
+        let nack = self.nack_stats().await;
+        let bandwidth = self.bandwidth_stats().await;
+
+        ClientInfo {
+            uid: uid.to_string(),
+            connection_state: self.peer_connection.connection_state().to_string(),
+            ice_state: self.peer_connection.ice_connection_state().to_string(),
+            age_s: now.duration_since(self.created).as_secs_f64(),
+            connected_s: self
+                .connected_at
+                .lock()
+                .unwrap()
+                .map(|at| now.duration_since(at).as_secs_f64()),
+            remote_addr: candidate_pair.as_ref().map(|p| p.remote_addr().to_string()),
+            candidate_pair,
+            streams,
+            nack,
+            lag: self.lag_stats(),
+            bandwidth,
+        }
+    }
+
     /**
      * Cleans up after a webrtc client has disconnected.
      * Takes ownership of self so no futher calls are possible.
      */
     pub async fn discard(&self) {
+        // Forget the signalling channel (and any pending negotiation) first,
+        // so no renegotiation starts while closing.
+        self.signal_channel.write().await.take();
+        self.negotiation_pending.store(false, Ordering::Relaxed);
         self.peer_connection.close().await.unwrap();
     }
 
+    /**
+     * NACKs received from this client, across all of its tracks.
+     */
+    pub async fn nack_stats(&self) -> NackStats {
+        self.nack_counter.write().await.stats(Instant::now())
+    }
+
+    pub async fn bandwidth_stats(&self) -> BandwidthStats {
+        self.bandwidth.read().await.stats()
+    }
+
+    /**
+     * How often this client fell behind its tracks, across all of them.
+     */
+    pub fn lag_stats(&self) -> LagStats {
+        *self.lag_stats.lock().unwrap()
+    }
+
+    /**
+     * How long this client has gone without ever connecting, if it hasn't yet.
+     */
+    pub fn unconnected_for(&self, now: Instant) -> Option<Duration> {
+        match *self.connected_at.lock().unwrap() {
+            Some(_) => None,
+            None => Some(now.duration_since(self.created)),
+        }
+    }
+
+    /**
+     * How long this client has been viewing no streams, if it isn't viewing any.
+     */
+    pub fn idle_for(&self, now: Instant) -> Option<Duration> {
+        self.idle_since
+            .lock()
+            .unwrap()
+            .map(|since| now.duration_since(since))
+    }
+
     pub fn watch_fail(&self) -> watch::Receiver<bool> {
         self.watch_failed.clone()
     }
@@ -251,11 +1558,25 @@ impl Client {
         // Only perform a re-sync if connected.
         if self.peer_connection.connection_state() == RTCPeerConnectionState::Connected {
             let streams = self.streams.read().await;
-            streams.get(&stream_id).unwrap().buffer.resync().await;
+            let video = streams.get(&stream_id).and_then(|t| t.video.as_ref());
+
+            // Streams paused by the client, or for lack of bandwidth, stay paused.
+            if let Some(video) = video.filter(|v| !v.is_paused()) {
+                video.buffer.resync().await;
+            }
         }
     }
 }
 
+/**
+ * The ICE username fragment of an SDP (the first one; bundled media sections share it).
+ * Changes with every ICE restart.
+ */
+fn ice_ufrag(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|l| l.trim().strip_prefix("a=ice-ufrag:"))
+}
+
 /*
 let t1 = new RtpStream(5000);
 
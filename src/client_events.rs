@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::bandwidth::BandwidthStats;
+use crate::buffered_track::LagStats;
+use crate::ingest_health::TrackState;
+use crate::retransmit::NackStats;
+
+// Label of the data channel events are sent to clients on.
+pub const EVENTS_CHANNEL: &str = "events";
+
+// Prefix of the labels of data channels carrying streams' data tracks ("data:<stream id>").
+pub const DATA_CHANNEL_PREFIX: &str = "data:";
+
+/**
+ * Events sent to a client over its events data channel, as JSON text messages.
+ * Each message is an object with a "type" field naming the event, plus the
+ * event's fields. See the README for the full schema.
+ */
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    // The buffered packets of a (re)started stream have all been sent,
+    // and live packets follow. Hides the fast-forward flash of the burst.
+    FastStartDone {
+        stream_id: String,
+    },
+
+    // A track of a stream the client is viewing changed state (ie went stalled or live).
+    StreamState {
+        stream_id: String,
+        track: String,
+        state: TrackState,
+    },
+
+    // A stream was paused for the client ("bandwidth": the client can't keep up with all of its streams,
+    // or "viewer": the client paused it).
+    StreamPaused {
+        stream_id: String,
+        reason: String,
+    },
+
+    // A paused stream was resumed.
+    StreamResumed {
+        stream_id: String,
+    },
+
+    // The client's video of a stream switched renditions (ie to a lower resolution, to save bandwidth).
+    // Sent once the new rendition is playing.
+    RenditionChanged {
+        stream_id: String,
+        rendition: String,
+    },
+
+    // A stream was removed from the client.
+    StreamRemoved {
+        stream_id: String,
+    },
+
+    // A command sent on a stream's control channel wasn't forwarded
+    // ("forbidden", "rate_limited" or why the endpoint failed).
+    ControlRejected {
+        stream_id: String,
+        reason: String,
+    },
+
+    // An operator set the streams the client views. The client should take these as its
+    // selected streams, so its next signalling request doesn't undo the change.
+    StreamsAssigned {
+        stream_ids: Vec<String>,
+    },
+
+    // An operator disconnected the client. The connection is about to be closed.
+    Kicked,
+
+    // The server is shutting down. The connection is about to be closed.
+    Shutdown,
+
+    // Periodic stats about the client's connection.
+    Stats {
+        nack: NackStats,
+        lag: LagStats,
+        bandwidth: BandwidthStats,
+    },
+}
+
+/**
+ * Commands clients send on the events data channel, as JSON text messages
+ * with a "type" field, like events.
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    // Stop sending a stream's video (ie while it's hidden). Its transceiver is kept.
+    Pause { stream_id: String },
+
+    // Resume a paused stream's video, from its latest keyframe.
+    Resume { stream_id: String },
+}
@@ -0,0 +1,143 @@
+// Per-client details served by /api/clients: the client's connection, and what it's being sent.
+// Mostly gathered from the client's peer connection stats.
+use std::collections::HashMap;
+
+use serde::Serialize;
+use webrtc::stats::{ICECandidateStats, StatsReport, StatsReportType};
+
+use crate::{bandwidth::BandwidthStats, buffered_track::LagStats, retransmit::NackStats};
+
+#[derive(Serialize)]
+pub struct ClientInfo {
+    pub uid: String,
+    pub connection_state: String,
+    pub ice_state: String,
+    pub age_s: f64,                  // Since the client first signalled
+    pub connected_s: Option<f64>,    // Since it first connected
+    pub remote_addr: Option<String>, // The client's end of the selected candidate pair
+    pub candidate_pair: Option<CandidatePairInfo>,
+    pub streams: Vec<ClientStreamInfo>,
+    pub nack: NackStats, // As in the client's stats events
+    pub lag: LagStats,
+    pub bandwidth: BandwidthStats,
+}
+
+/**
+ * The candidate pair a client's media is sent over.
+ */
+#[derive(Serialize)]
+pub struct CandidatePairInfo {
+    local: CandidateInfo,
+    remote: CandidateInfo,
+    rtt_ms: f64, // From ICE consent checks
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+#[derive(Serialize)]
+pub struct CandidateInfo {
+    address: String,        // ip:port
+    candidate_type: String, // host, srflx, prflx or relay
+    network_type: String,   // ie udp4
+}
+
+impl From<&ICECandidateStats> for CandidateInfo {
+    fn from(c: &ICECandidateStats) -> Self {
+        CandidateInfo {
+            address: format!("{}:{}", c.ip, c.port),
+            candidate_type: c.candidate_type.to_string(),
+            network_type: c.network_type.to_string(),
+        }
+    }
+}
+
+/**
+ * A stream a client views, and how it's being sent.
+ */
+#[derive(Serialize)]
+pub struct ClientStreamInfo {
+    pub stream_id: String,
+    pub mid: Option<String>, // Of the transceiver sending the stream's video
+    pub rendition: Option<String>, // Of the video being sent
+    pub thumbnail: bool,
+    pub paused: Option<String>, // Why the video isn't being sent ("viewer" or "bandwidth")
+    pub data_channel: bool,
+    pub control_channel: bool,
+    pub video: Option<TrackSendStats>,
+}
+
+/**
+ * What's been sent on a track, and what the client's receiver reports say about it.
+ */
+#[derive(Serialize, Default)]
+pub struct TrackSendStats {
+    ssrc: u32,
+    bytes_sent: u64, // Payload and headers
+    packets_sent: u64,
+    nack_count: u64,
+    pli_count: u64,
+    packets_lost: i64,
+    fraction_lost: f64,
+    pub jitter_ms: Option<f64>,
+    rtt_ms: Option<f64>,
+}
+
+/**
+ * The selected (nominated) candidate pair in a connection's stats.
+ */
+pub fn candidate_pair(stats: &StatsReport) -> Option<CandidatePairInfo> {
+    let pair = stats.reports.values().find_map(|r| match r {
+        StatsReportType::CandidatePair(p) if p.nominated => Some(p),
+        _ => None,
+    })?;
+
+    let candidate = |id: &str| match stats.reports.get(id) {
+        Some(StatsReportType::LocalCandidate(c)) | Some(StatsReportType::RemoteCandidate(c)) => {
+            Some(CandidateInfo::from(c))
+        }
+        _ => None,
+    };
+
+    Some(CandidatePairInfo {
+        local: candidate(&pair.local_candidate_id)?,
+        remote: candidate(&pair.remote_candidate_id)?,
+        rtt_ms: pair.current_round_trip_time * 1000.0,
+        bytes_sent: pair.bytes_sent,
+        bytes_received: pair.bytes_received,
+    })
+}
+
+impl CandidatePairInfo {
+    pub fn remote_addr(&self) -> &str {
+        &self.remote.address
+    }
+}
+
+/**
+ * Send stats of every outgoing track in a connection's stats, keyed by SSRC.
+ * Jitter isn't in webrtc-rs' stats, so it's left for the caller to fill in.
+ */
+pub fn track_stats(stats: &StatsReport) -> HashMap<u32, TrackSendStats> {
+    let mut tracks: HashMap<u32, TrackSendStats> = HashMap::new();
+    for report in stats.reports.values() {
+        match report {
+            StatsReportType::OutboundRTP(s) => {
+                let t = tracks.entry(s.ssrc).or_default();
+                t.ssrc = s.ssrc;
+                t.bytes_sent = s.bytes_sent + s.header_bytes_sent;
+                t.packets_sent = s.packets_sent;
+                t.nack_count = s.nack_count;
+                t.pli_count = s.pli_count.unwrap_or(0);
+            }
+            StatsReportType::RemoteInboundRTP(s) => {
+                let t = tracks.entry(s.ssrc).or_default();
+                t.ssrc = s.ssrc;
+                t.packets_lost = s.packets_lost;
+                t.fraction_lost = s.fraction_lost;
+                t.rtt_ms = s.round_trip_time.map(|rtt| rtt * 1000.0);
+            }
+            _ => (),
+        }
+    }
+    tracks
+}
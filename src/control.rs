@@ -0,0 +1,380 @@
+// Viewer -> endpoint command forwarding (ie PTZ or drive commands).
+// Every viewer of a stream with a control endpoint gets its own control data channel
+// and link to the endpoint, so responses make it back to the viewer that sent the command.
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Instant};
+use webrtc::{
+    data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel},
+    peer_connection::RTCPeerConnection,
+};
+
+use crate::server::token_matches;
+use crate::ControlDef;
+
+// Prefix of the labels of viewers' control data channels ("control:<stream id>").
+pub const CONTROL_CHANNEL_PREFIX: &str = "control:";
+
+const DEFAULT_RATE_LIMIT: u32 = 20;
+
+// Largest accepted UDP datagram.
+const MAX_DATAGRAM: usize = 65535;
+
+// Largest relayed HTTP response, headers included.
+const MAX_HTTP_RESPONSE: u64 = 64 * 1024;
+
+// How long an HTTP endpoint has to respond to a command.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+ * Token bucket limiting how fast a viewer can send commands.
+ */
+struct RateLimiter {
+    rate: f64,  // Tokens per second
+    burst: f64, // Bucket size
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            tokens: burst.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/**
+ * An http:// URL, split into what's needed to connect and send the request line.
+ */
+struct HttpEndpoint {
+    host: String,      // Host header
+    authority: String, // host:port to connect to
+    path: String,
+}
+
+impl HttpEndpoint {
+    fn parse(url: &str) -> Result<HttpEndpoint> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or(anyhow!("Only http:// control URLs are supported: {}", url))?;
+
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+
+        let authority = match host.rsplit_once(':') {
+            Some((_, port)) if !port.contains(']') => host.to_string(),
+            _ => format!("{}:80", host),
+        };
+
+        Ok(HttpEndpoint {
+            host: host.to_string(),
+            authority,
+            path: path.to_string(),
+        })
+    }
+
+    /**
+     * POSTs the passed body and returns the response body.
+     * HTTP/1.0 keeps the response simple to read: no chunking, closed when done.
+     */
+    async fn post(&self, body: &[u8], content_type: &str) -> Result<Bytes> {
+        let request = async {
+            let mut stream = TcpStream::connect(&self.authority).await?;
+
+            let head = format!(
+                "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                self.path,
+                self.host,
+                content_type,
+                body.len()
+            );
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body).await?;
+
+            let mut response = Vec::new();
+            stream
+                .take(MAX_HTTP_RESPONSE)
+                .read_to_end(&mut response)
+                .await?;
+            Ok::<Vec<u8>, anyhow::Error>(response)
+        };
+
+        let response = timeout(HTTP_TIMEOUT, request)
+            .await
+            .map_err(|_| anyhow!("Control endpoint timed out"))??;
+
+        let head_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(anyhow!("Malformed response from control endpoint"))?;
+
+        let status = String::from_utf8_lossy(&response[..head_end])
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or(anyhow!("Malformed response from control endpoint"))?;
+
+        if !(200..300).contains(&status) {
+            bail!("Control endpoint responded {}", status);
+        }
+
+        Ok(Bytes::copy_from_slice(&response[head_end + 4..]))
+    }
+}
+
+enum Endpoint {
+    Udp(UdpSocket),
+    Http(HttpEndpoint),
+}
+
+/**
+ * A viewer's control data channel for a stream, and its link to the stream's control endpoint.
+ * Dropping the link stops relaying responses.
+ */
+pub struct ControlLink {
+    pub stream_id: String,
+    channel: Arc<RTCDataChannel>,
+    endpoint: Arc<Endpoint>,
+    tokens: Vec<String>,
+    limiter: std::sync::Mutex<RateLimiter>,
+    _closed: oneshot::Sender<()>, // Dropped with the link, which stops the UDP response relay
+}
+
+impl ControlLink {
+    /**
+     * Opens a control data channel for the passed stream on the passed peer connection.
+     * Commands are only forwarded in order and reliably.
+     */
+    pub async fn open(
+        pc: &RTCPeerConnection,
+        stream_id: &str,
+        def: &ControlDef,
+    ) -> Result<Arc<ControlLink>> {
+        let endpoint = match (def.udp, def.url.as_deref()) {
+            (Some(addr), _) => {
+                // A socket per viewer, so the endpoint's responses go back to the right one.
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let sock = UdpSocket::bind(local).await?;
+                sock.connect(addr).await?;
+                Endpoint::Udp(sock)
+            }
+            (None, Some(url)) => Endpoint::Http(HttpEndpoint::parse(url)?),
+            (None, None) => bail!(
+                "Control endpoint of {} needs a udp address or url",
+                stream_id
+            ),
+        };
+
+        let channel = pc
+            .create_data_channel(&format!("{}{}", CONTROL_CHANNEL_PREFIX, stream_id), None)
+            .await?;
+
+        let rate = def.rate_limit.unwrap_or(DEFAULT_RATE_LIMIT);
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let link = Arc::new(ControlLink {
+            stream_id: stream_id.to_string(),
+            channel,
+            endpoint: Arc::new(endpoint),
+            tokens: def.tokens.clone(),
+            limiter: std::sync::Mutex::new(RateLimiter::new(rate, def.burst.unwrap_or(rate))),
+            _closed: closed_tx,
+        });
+
+        if let Endpoint::Udp(_) = *link.endpoint {
+            ControlLink::task_udp_relay(
+                link.endpoint.clone(),
+                Arc::downgrade(&link.channel),
+                closed_rx,
+            );
+        }
+
+        Ok(link)
+    }
+
+    /**
+     * UDP response relay task. Every datagram the endpoint sends back is relayed to the viewer.
+     * Exits once the link is dropped.
+     */
+    fn task_udp_relay(
+        endpoint: Arc<Endpoint>,
+        channel: Weak<RTCDataChannel>,
+        mut closed: oneshot::Receiver<()>,
+    ) {
+        tokio::spawn(async move {
+            let sock = match *endpoint {
+                Endpoint::Udp(ref sock) => sock,
+                Endpoint::Http(_) => return,
+            };
+
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                let n = select! {
+                    res = sock.recv(&mut buf) => match res {
+                        Ok(n) => n,
+                        // ie ICMP port unreachable, if nothing's listening at the endpoint
+                        Err(_) => continue,
+                    },
+                    _ = &mut closed => break,
+                };
+
+                match channel.upgrade() {
+                    Some(dc) => {
+                        dc.send(&Bytes::copy_from_slice(&buf[..n])).await.ok();
+                    }
+                    None => break,
+                }
+            }
+            println!("Control relay exited.");
+        });
+    }
+
+    pub fn channel(&self) -> &Arc<RTCDataChannel> {
+        &self.channel
+    }
+
+    /**
+     * Whether a viewer holding the passed token may send commands.
+     */
+    pub fn permits(&self, token: Option<&str>) -> bool {
+        self.tokens.is_empty()
+            || token.is_some_and(|t| self.tokens.iter().any(|allowed| token_matches(t, allowed)))
+    }
+
+    /**
+     * Takes a command from the viewer's rate limit. False if it's been exceeded.
+     */
+    pub fn allow(&self) -> bool {
+        self.limiter.lock().unwrap().allow(Instant::now())
+    }
+
+    /**
+     * Forwards a command to the endpoint. HTTP response bodies are relayed back
+     * as the same kind (text/binary) of message as the command.
+     */
+    pub async fn forward(&self, msg: &DataChannelMessage) -> Result<()> {
+        match *self.endpoint {
+            Endpoint::Udp(ref sock) => {
+                sock.send(&msg.data).await?;
+            }
+            Endpoint::Http(ref http) => {
+                let content_type = match msg.is_string {
+                    true => "text/plain; charset=utf-8",
+                    false => "application/octet-stream",
+                };
+
+                let response = http.post(&msg.data, content_type).await?;
+                if response.is_empty() {
+                    return Ok(());
+                }
+
+                match (msg.is_string, String::from_utf8(response.to_vec())) {
+                    (true, Ok(text)) => self.channel.send_text(text).await?,
+                    _ => self.channel.send(&response).await?,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.channel.close().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_allows_burst_then_refills() {
+        let mut limiter = RateLimiter::new(10, 3);
+        let start = limiter.last;
+
+        for _ in 0..3 {
+            assert!(limiter.allow(start));
+        }
+        assert!(!limiter.allow(start));
+
+        // One token every 100ms.
+        assert!(!limiter.allow(start + Duration::from_millis(50)));
+        assert!(limiter.allow(start + Duration::from_millis(100)));
+        assert!(!limiter.allow(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn limiter_refills_up_to_burst() {
+        let mut limiter = RateLimiter::new(10, 3);
+        let later = limiter.last + Duration::from_secs(60);
+
+        for _ in 0..3 {
+            assert!(limiter.allow(later));
+        }
+        assert!(!limiter.allow(later));
+    }
+
+    #[test]
+    fn limiter_burst_at_least_one() {
+        let mut limiter = RateLimiter::new(1, 0);
+        let start = limiter.last;
+        assert!(limiter.allow(start));
+        assert!(!limiter.allow(start));
+    }
+
+    #[test]
+    fn parses_http_endpoints() {
+        let e = HttpEndpoint::parse("http://cam.local/ptz?preset=1").unwrap();
+        assert_eq!(e.host, "cam.local");
+        assert_eq!(e.authority, "cam.local:80");
+        assert_eq!(e.path, "/ptz?preset=1");
+
+        let e = HttpEndpoint::parse("http://10.0.0.5:8080").unwrap();
+        assert_eq!(e.host, "10.0.0.5:8080");
+        assert_eq!(e.authority, "10.0.0.5:8080");
+        assert_eq!(e.path, "/");
+
+        let e = HttpEndpoint::parse("http://[::1]/drive").unwrap();
+        assert_eq!(e.host, "[::1]");
+        assert_eq!(e.authority, "[::1]:80");
+
+        let e = HttpEndpoint::parse("http://[::1]:9000/drive").unwrap();
+        assert_eq!(e.authority, "[::1]:9000");
+        assert_eq!(e.path, "/drive");
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(HttpEndpoint::parse("https://cam.local/ptz").is_err());
+        assert!(HttpEndpoint::parse("udp://cam.local:5000").is_err());
+        assert!(HttpEndpoint::parse("cam.local/ptz").is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::RwLock;
+
+use crate::net_util::listen_udp;
+use crate::{DataDef, DataProtocol};
+
+const MAX_MESSAGES: usize = 1000;
+
+// Largest accepted UDP datagram.
+const MAX_DATAGRAM: usize = 65535;
+
+// Longest accepted line from a TCP source. A source sending a longer one is disconnected,
+// rather than buffering it without limit.
+const MAX_LINE: usize = 65535;
+
+// How long to wait before reconnecting to a TCP source that went away.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/**
+ * Handles the ingestion of a stream's telemetry/data track.
+ * Every UDP datagram (or line, for TCP sources) is one message, broadcast
+ * to every client viewing the stream over a data channel.
+ */
+pub struct DataTrack {
+    pub def: DataDef,
+    subscriber: Receiver<Bytes>,
+    last: Arc<RwLock<Option<Bytes>>>,
+}
+
+impl DataTrack {
+    pub fn new(def: &DataDef) -> Result<DataTrack> {
+        let (tx, subscriber) = broadcast::channel::<Bytes>(MAX_MESSAGES);
+        let last = Arc::new(RwLock::new(None));
+
+        match def.protocol {
+            DataProtocol::Udp => {
+                let sock = listen_udp(&def.socket_addr(), def.interface.as_deref(), def.source)
+                    .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", def.socket_addr(), e))?;
+                DataTrack::task_udp_reader(Arc::downgrade(&last), UdpSocket::from_std(sock)?, tx);
+            }
+            DataProtocol::Tcp => {
+                DataTrack::task_tcp_reader(Arc::downgrade(&last), def.socket_addr(), tx);
+            }
+        }
+
+        Ok(DataTrack {
+            def: def.clone(),
+            subscriber,
+            last,
+        })
+    }
+
+    /**
+     * UDP reader task. Every datagram is a message.
+     * Exits once the parent track is deleted.
+     */
+    fn task_udp_reader(last: Weak<RwLock<Option<Bytes>>>, sock: UdpSocket, tx: Sender<Bytes>) {
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                let n = match sock.recv_from(&mut buf).await {
+                    Ok((n, _)) => n,
+                    Err(_) => {
+                        println!("Problem receiving from data socket");
+                        continue;
+                    }
+                };
+
+                if !DataTrack::dispatch(&last, &tx, Bytes::copy_from_slice(&buf[..n])).await {
+                    break;
+                }
+            }
+            println!("Data reader exited.")
+        });
+    }
+
+    /**
+     * TCP reader task. Connects to the source and treats every line as a message,
+     * reconnecting if the connection drops. Exits once the parent track is deleted.
+     */
+    fn task_tcp_reader(last: Weak<RwLock<Option<Bytes>>>, addr: SocketAddr, tx: Sender<Bytes>) {
+        tokio::spawn(async move {
+            'connect: while last.strong_count() > 0 {
+                let stream = match TcpStream::connect(addr).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        println!("Couldn't connect to data source {}: {}", addr, e);
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                        continue;
+                    }
+                };
+
+                let mut reader = BufReader::new(stream);
+                loop {
+                    let line = match DataTrack::read_line(&mut reader).await {
+                        Ok(Some(line)) => line,
+                        Ok(None) => {
+                            println!("Data source {} disconnected", addr);
+                            tokio::time::sleep(RECONNECT_INTERVAL).await;
+                            continue 'connect;
+                        }
+                        Err(e) => {
+                            println!("Dropping data source {}: {}", addr, e);
+                            tokio::time::sleep(RECONNECT_INTERVAL).await;
+                            continue 'connect;
+                        }
+                    };
+
+                    if !DataTrack::dispatch(&last, &tx, line).await {
+                        break 'connect;
+                    }
+                }
+            }
+            println!("Data reader exited.")
+        });
+    }
+
+    /**
+     * Reads a line, without its line ending. Returns None at the end of the stream,
+     * and an error if the line is longer than MAX_LINE.
+     */
+    async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Bytes>> {
+        let mut line = Vec::new();
+        let n = reader
+            .take(MAX_LINE as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if n == 0 {
+            return Ok(None);
+        }
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        } else if line.len() > MAX_LINE {
+            return Err(anyhow::Error::msg(format!("line over {} bytes", MAX_LINE)));
+        }
+        Ok(Some(Bytes::from(line)))
+    }
+
+    /**
+     * Stores (for replay) and broadcasts a message.
+     * Returns false if the parent track has been deleted and the reader should exit.
+     */
+    async fn dispatch(last: &Weak<RwLock<Option<Bytes>>>, tx: &Sender<Bytes>, msg: Bytes) -> bool {
+        match last.upgrade() {
+            Some(l) => *l.write().await = Some(msg.clone()),
+            None => return false,
+        }
+
+        // An error just means no client is listening right now.
+        tx.send(msg).ok();
+        true
+    }
+
+    /**
+     * The most recent message, if any. Sent to clients as they join,
+     * if the track is configured to replay it.
+     */
+    pub async fn last(&self) -> Option<Bytes> {
+        match self.def.replay_last {
+            true => self.last.read().await.clone(),
+            false => None,
+        }
+    }
+
+    /**
+     * Returns a new broadcast handle that distributes this track's messages as they're received.
+     */
+    pub fn subscribe(&self) -> Receiver<Bytes> {
+        self.subscriber.resubscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_lines() {
+        let mut reader: &[u8] = b"first\nsecond\r\n\nlast";
+        assert_eq!(
+            DataTrack::read_line(&mut reader).await.unwrap(),
+            Some(Bytes::from("first"))
+        );
+        assert_eq!(
+            DataTrack::read_line(&mut reader).await.unwrap(),
+            Some(Bytes::from("second"))
+        );
+        assert_eq!(
+            DataTrack::read_line(&mut reader).await.unwrap(),
+            Some(Bytes::new())
+        );
+        assert_eq!(
+            DataTrack::read_line(&mut reader).await.unwrap(),
+            Some(Bytes::from("last"))
+        );
+        assert_eq!(DataTrack::read_line(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_long_lines() {
+        let mut data = vec![b'a'; MAX_LINE];
+        data.push(b'\n');
+        let mut reader: &[u8] = &data;
+        let line = DataTrack::read_line(&mut reader).await.unwrap().unwrap();
+        assert_eq!(line.len(), MAX_LINE);
+
+        let mut data = vec![b'a'; MAX_LINE + 1];
+        data.push(b'\n');
+        let mut reader: &[u8] = &data;
+        assert!(DataTrack::read_line(&mut reader).await.is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use webrtc::rtp::packet::Packet;
+
+use crate::rewriter::Rewriter;
+use crate::TrackDef;
+
+// How long an input can go without packets before it's considered dead.
+pub const DEFAULT_FAILOVER_MS: u64 = 2000;
+
+/**
+ * Picks which of a track's redundant inputs is forwarded.
+ * Inputs are prioritized in order (primary first). When the active input goes
+ * silent, the next live input takes over at its next keyframe. When a higher
+ * priority input comes back, it takes over again at its next keyframe.
+ *
+ * Sequence numbers and timestamps of the forwarded input are rewritten so that
+ * the output looks like one continuous RTP stream to clients.
+ */
+pub struct Failover {
+    timeout: Duration,
+
+    last_seen: Vec<Option<Instant>>,
+    active: usize,
+
+    rewriter: Rewriter,
+}
+
+impl Failover {
+    pub fn new(timeout: Duration, clock_rate: u32) -> Failover {
+        Failover {
+            timeout,
+            last_seen: Vec::new(),
+            active: 0,
+            rewriter: Rewriter::new(clock_rate),
+        }
+    }
+
+    fn is_alive(&self, input: usize, now: Instant) -> bool {
+        self.last_seen[input].is_some_and(|t| now.duration_since(t) < self.timeout)
+    }
+
+    /**
+     * Handles a packet received on the passed input.
+     * Returns the (rewritten) packet if it should be forwarded.
+     */
+    pub fn process(
+        &mut self,
+        input: usize,
+        pkt: Arc<Packet>,
+        def: &TrackDef,
+        now: Instant,
+    ) -> Option<Arc<Packet>> {
+        if input >= self.last_seen.len() {
+            self.last_seen.resize(input + 1, None);
+        }
+        self.last_seen[input] = Some(now);
+
+        if input != self.active {
+            // Highest priority input that's still receiving data.
+            let preferred = (0..self.last_seen.len())
+                .find(|&i| self.is_alive(i, now))
+                .unwrap_or(self.active);
+
+            // Only switch on a keyframe, otherwise clients would be fed
+            // delta frames that reference a different encoder's pictures.
+            if input != preferred || !def.is_keyframe(&pkt) {
+                return None;
+            }
+
+            self.switch_to(input);
+        }
+
+        Some(self.rewriter.rewrite(&pkt, now))
+    }
+
+    /**
+     * Makes the passed input active. Its first packet directly follows
+     * the last packet forwarded from the previous input.
+     */
+    fn switch_to(&mut self, input: usize) {
+        println!(
+            "Failover: switching from input {} to {}",
+            self.active, input
+        );
+
+        self.rewriter.restart();
+        self.active = input;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use webrtc::rtp::header::Header;
+
+    const CLOCK_RATE: u32 = 90000;
+
+    fn def() -> TrackDef {
+        TrackDef {
+            codec: "h264".to_string(),
+            ..Default::default()
+        }
+    }
+
+    // An H264 IDR (keyframe) or non-IDR slice, as a single NAL unit packet.
+    fn pkt(seq: u16, ts: u32, keyframe: bool) -> Arc<Packet> {
+        let nal = if keyframe { 0x65 } else { 0x41 };
+        Arc::new(Packet {
+            header: Header {
+                sequence_number: seq,
+                timestamp: ts,
+                ..Default::default()
+            },
+            payload: Bytes::from(vec![nal, 0x88]),
+        })
+    }
+
+    fn failover() -> Failover {
+        Failover::new(Duration::from_millis(DEFAULT_FAILOVER_MS), CLOCK_RATE)
+    }
+
+    #[test]
+    fn forwards_primary_unchanged() {
+        let mut f = failover();
+        let now = Instant::now();
+
+        let out = f.process(0, pkt(100, 5000, false), &def(), now).unwrap();
+        assert_eq!(out.header.sequence_number, 100);
+        assert_eq!(out.header.timestamp, 5000);
+
+        // The backup is ignored while the primary is live, keyframe or not.
+        assert!(f.process(1, pkt(7, 1000, true), &def(), now).is_none());
+    }
+
+    #[test]
+    fn switches_with_continuous_seq_and_ts() {
+        let mut f = failover();
+        let start = Instant::now();
+
+        f.process(0, pkt(100, 5000, true), &def(), start).unwrap();
+        f.process(0, pkt(101, 8000, false), &def(), start).unwrap();
+
+        // Primary goes silent. The backup only takes over at a keyframe.
+        let later = start + Duration::from_millis(DEFAULT_FAILOVER_MS + 500);
+        assert!(f
+            .process(1, pkt(40000, 1_000_000, false), &def(), later)
+            .is_none());
+
+        let out = f
+            .process(1, pkt(40001, 1_003_000, true), &def(), later)
+            .unwrap();
+        assert_eq!(out.header.sequence_number, 102);
+        let elapsed = later.duration_since(start).as_secs_f64();
+        assert_eq!(
+            out.header.timestamp,
+            8000 + (elapsed * CLOCK_RATE as f64) as u32
+        );
+
+        // And continues on from there.
+        let next = f
+            .process(1, pkt(40002, 1_006_000, false), &def(), later)
+            .unwrap();
+        assert_eq!(next.header.sequence_number, 103);
+        assert_eq!(next.header.timestamp, out.header.timestamp + 3000);
+    }
+
+    #[test]
+    fn returns_to_primary_at_its_keyframe() {
+        let mut f = failover();
+        let start = Instant::now();
+
+        f.process(0, pkt(100, 5000, true), &def(), start).unwrap();
+        let later = start + Duration::from_millis(DEFAULT_FAILOVER_MS + 500);
+        let backup = f
+            .process(1, pkt(40000, 1_000_000, true), &def(), later)
+            .unwrap();
+
+        // Primary is back, but backup packets are forwarded until the primary's keyframe.
+        assert!(f
+            .process(0, pkt(200, 90_000, false), &def(), later)
+            .is_none());
+        let out = f
+            .process(1, pkt(40001, 1_003_000, false), &def(), later)
+            .unwrap();
+        assert_eq!(
+            out.header.sequence_number,
+            backup.header.sequence_number + 1
+        );
+
+        let back = f.process(0, pkt(201, 93_000, true), &def(), later).unwrap();
+        assert_eq!(back.header.sequence_number, out.header.sequence_number + 1);
+        assert!(back.header.timestamp.wrapping_sub(out.header.timestamp) >= 1);
+        assert!(f
+            .process(1, pkt(40002, 1_006_000, false), &def(), later)
+            .is_none());
+    }
+
+    #[test]
+    fn sequence_wraps_across_switch() {
+        let mut f = failover();
+        let start = Instant::now();
+
+        f.process(0, pkt(65535, u32::MAX, true), &def(), start)
+            .unwrap();
+        let later = start + Duration::from_millis(DEFAULT_FAILOVER_MS + 1);
+        let out = f.process(1, pkt(10, 0, true), &def(), later).unwrap();
+        assert_eq!(out.header.sequence_number, 0);
+    }
+}
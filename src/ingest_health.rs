@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+use webrtc::rtp::packet::Packet;
+use webrtc::util::MarshalSize;
+
+use crate::rate_counter::RateCounter;
+
+// A track that hasn't received a packet in this long is considered stalled.
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A track that's receiving packets but hasn't seen a keyframe in this long
+// can't be displayed by newly connecting clients.
+const KEYFRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often track states are re-evaluated for state change events.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackState {
+    Waiting,    // No packets have been received yet.
+    Live,       // Packets and keyframes are coming in.
+    Stalled,    // Packets were being received, but stopped.
+    NoKeyframe, // Packets are being received, but no (recent) keyframe.
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TrackStatus {
+    state: TrackState,
+    since_last_packet_ms: Option<u64>,
+    packets_per_sec: f64,
+    bytes_per_sec: f64,
+    keyframe_interval_ms: Option<u64>,
+}
+
+impl TrackStatus {
+    pub fn state(&self) -> TrackState {
+        self.state
+    }
+
+    pub fn bytes_per_sec(&self) -> f64 {
+        self.bytes_per_sec
+    }
+}
+
+/**
+ * Emitted whenever a track's state changes.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct TrackStateEvent {
+    pub stream_id: String,
+    pub track: String,
+    pub state: TrackState,
+}
+
+/**
+ * Liveness bookkeeping for an RtpTrack. Updated by the RTP reader for every
+ * packet the track forwards.
+ */
+pub struct IngestHealth {
+    last_packet: Option<Instant>,
+
+    // Arrival time and RTP timestamp of the most recent keyframe.
+    last_keyframe: Option<(Instant, u32)>,
+    keyframe_interval: Option<Duration>,
+
+    packets: RateCounter,
+    bytes: RateCounter,
+}
+
+impl IngestHealth {
+    pub fn new() -> IngestHealth {
+        IngestHealth {
+            last_packet: None,
+            last_keyframe: None,
+            keyframe_interval: None,
+            packets: RateCounter::new(),
+            bytes: RateCounter::new(),
+        }
+    }
+
+    pub fn record(&mut self, pkt: &Packet, is_keyframe: bool, now: Instant) {
+        self.last_packet = Some(now);
+        self.packets.add(1, now);
+        self.bytes.add(pkt.marshal_size() as u64, now);
+
+        // A keyframe may span several packets, all sharing the same timestamp.
+        if is_keyframe {
+            match self.last_keyframe {
+                Some((_, ts)) if ts == pkt.header.timestamp => (),
+                Some((at, _)) => {
+                    self.keyframe_interval = Some(now.duration_since(at));
+                    self.last_keyframe = Some((now, pkt.header.timestamp));
+                }
+                None => self.last_keyframe = Some((now, pkt.header.timestamp)),
+            }
+        }
+    }
+
+    /**
+     * How long until the next keyframe is expected, going by the interval between the last two.
+     * None if the interval isn't known yet, or the keyframe is overdue.
+     */
+    pub fn next_keyframe_in(&self, now: Instant) -> Option<Duration> {
+        let (at, _) = self.last_keyframe?;
+        (at + self.keyframe_interval?).checked_duration_since(now)
+    }
+
+    pub fn state(&self, now: Instant) -> TrackState {
+        let last_packet = match self.last_packet {
+            Some(t) => t,
+            None => return TrackState::Waiting,
+        };
+
+        if now.duration_since(last_packet) > STALL_TIMEOUT {
+            return TrackState::Stalled;
+        }
+
+        match self.last_keyframe {
+            Some((t, _)) if now.duration_since(t) <= KEYFRAME_TIMEOUT => TrackState::Live,
+            _ => TrackState::NoKeyframe,
+        }
+    }
+
+    pub fn status(&mut self, now: Instant) -> TrackStatus {
+        TrackStatus {
+            state: self.state(now),
+            since_last_packet_ms: self
+                .last_packet
+                .map(|t| now.duration_since(t).as_millis() as u64),
+            packets_per_sec: self.packets.per_sec(now),
+            bytes_per_sec: self.bytes.per_sec(now),
+            keyframe_interval_ms: self.keyframe_interval.map(|d| d.as_millis() as u64),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::Result;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use webrtc::rtp::packet::Packet;
+use webrtc::util::Unmarshal;
+
+use crate::net_util::listen_udp;
+
+const MAX_PACKETS: usize = 10000;
+const MAX_RTP_SIZE: usize = 1200;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/**
+ * Everything that determines how an ingest socket is opened.
+ * Tracks with equal IngestAddrs share a socket.
+ */
+#[derive(Hash, Clone, PartialEq, Eq, Debug)]
+pub struct IngestAddr {
+    pub addr: SocketAddr,
+    pub interface: Option<String>, // Interface (name or address) to join multicast groups on.
+    pub source: Option<IpAddr>,    // Source address for source-specific multicast joins.
+}
+
+/**
+ * A bound (or joined) UDP socket that RTP packets are read from.
+ * Parsed packets are broadcast to every RtpTrack listening on the socket,
+ * and each track filters out the packets it's interested in (SSRC/payload type).
+ * This lets a single RTP session carrying several streams be split up.
+ */
+pub struct IngestSocket {
+    tx: Sender<Arc<Packet>>,
+}
+
+impl IngestSocket {
+    fn bind(addr: &IngestAddr) -> Result<Arc<IngestSocket>> {
+        let sock = listen_udp(&addr.addr, addr.interface.as_deref(), addr.source)
+            .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", addr.addr, e))?;
+        let sock = UdpSocket::from_std(sock)?;
+
+        let (tx, _) = broadcast::channel::<Arc<Packet>>(MAX_PACKETS);
+        let s = Arc::new(IngestSocket { tx });
+
+        IngestSocket::task_socket_reader(Arc::downgrade(&s), sock);
+
+        Ok(s)
+    }
+
+    /**
+     * Socket reader task. Parses incoming datagrams as RTP and distributes them
+     * to subscribed RtpTracks. Exits once every track using the socket is gone.
+     */
+    fn task_socket_reader(socket: Weak<IngestSocket>, sock: UdpSocket) {
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let mut warned_oversize = false;
+            loop {
+                let n = match sock.recv_from(&mut buf).await {
+                    Ok((n, _)) => n,
+                    Err(_) => {
+                        println!("Problem receiving from UDP socket");
+                        continue;
+                    }
+                };
+
+                // Too big to forward over WebRTC without fragmenting. Drop it, but keep
+                // reading - other tracks may share this socket.
+                if n > MAX_RTP_SIZE {
+                    if !warned_oversize {
+                        println!(
+                            "ERROR: Received an RTP packet greater than {0} bytes! Dropping it.
+                    Make sure your format address specifies a max packet size of {0}!!
+                    (Hint: try adding `-pkt_size 1200` to your FFMPEG command)",
+                            MAX_RTP_SIZE
+                        );
+                        warned_oversize = true;
+                    }
+                    continue;
+                }
+
+                // Parse the incoming data into a Packet struct
+                // using WebRtc-rs's unmarshal to access RTP information.
+                let mut b: &[u8] = &buf[..n];
+                let pkt = match Packet::unmarshal(&mut b) {
+                    Ok(p) => Arc::new(p),
+                    Err(e) => {
+                        println!("Dropping malformed RTP packet: {}", e);
+                        continue;
+                    }
+                };
+
+                match socket.upgrade() {
+                    // An error here just means no track is listening right now.
+                    Some(s) => s.tx.send(pkt).ok(),
+                    None => break,
+                };
+            }
+            println!("Ingest socket reader exited.")
+        });
+    }
+
+    pub fn subscribe(&self) -> Receiver<Arc<Packet>> {
+        self.tx.subscribe()
+    }
+}
+
+/**
+ * Keeps track of open ingest sockets so tracks that listen on the same
+ * address share one socket instead of each binding their own.
+ */
+pub struct IngestSockets {
+    sockets: Mutex<HashMap<IngestAddr, Weak<IngestSocket>>>,
+}
+
+impl IngestSockets {
+    pub fn new() -> IngestSockets {
+        IngestSockets {
+            sockets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Returns the socket listening on the passed address, opening it if
+     * no live track is using it yet.
+     */
+    pub fn get_or_bind(&self, addr: &IngestAddr) -> Result<Arc<IngestSocket>> {
+        let mut sockets = self.sockets.lock().unwrap();
+
+        // Forget sockets whose tracks have all been dropped.
+        sockets.retain(|_, s| s.strong_count() > 0);
+
+        if let Some(s) = sockets.get(addr).and_then(|s| s.upgrade()) {
+            return Ok(s);
+        }
+
+        let s = IngestSocket::bind(addr)?;
+        sockets.insert(addr.clone(), Arc::downgrade(&s));
+
+        Ok(s)
+    }
+}
@@ -1,14 +1,22 @@
 mod app_controller;
+mod bandwidth;
 
 mod rtp_track;
 //mod server;
 mod buffered_track;
 mod stream_peer;
 use std::{
+    fs,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 mod stats;
+use app_controller::ClientLimits;
+use failover::DEFAULT_FAILOVER_MS;
+use ingest_socket::IngestAddr;
+use rtc_config::RtcConfig;
 use serde::{Serialize, Deserialize};
 use stream_manager::StreamManager;
 use structopt::StructOpt;
@@ -17,18 +25,41 @@ use webrtc::{
     rtp::packet::Packet,
 };
 mod client;
+mod client_events;
+mod client_info;
+mod control;
+mod data_track;
+mod failover;
+mod ingest_health;
+mod ingest_socket;
 mod net_util;
+mod rate_counter;
+mod reorder_buffer;
+mod retransmit;
+mod rewriter;
+mod rtc_config;
 mod server;
 mod stream_manager;
+mod thumbnail;
+mod whep;
 #[derive(Debug, StructOpt)]
 #[structopt(name = "example", about = "An example of StructOpt usage.")]
 struct Opt {
-    /// File name: only required when `out-type` is set to `file`
-    #[structopt(name = "Offer")]
-    offer: String,
+    /// Config file. Defines stream inputs accessible by clients and WebRTC settings.
+    #[structopt(short = "c", long = "config")]
+    config: Option<PathBuf>,
 }
 
-pub struct Config {}
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    streams: Vec<StreamDef>,
+    #[serde(default)]
+    rtc: RtcConfig,
+    #[serde(default)]
+    limits: ClientLimits,
+    admin_token: Option<String>, // Bearer token required by the operator endpoints. Disabled if unset
+}
 
 #[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct StreamDef {
@@ -36,13 +67,125 @@ pub struct StreamDef {
     default: bool, // Added by default when a new client connects?
     video: Option<TrackDef>,
     audio: Option<TrackDef>,
+    data: Option<DataDef>, // Telemetry forwarded to viewers over a data channel
+    control: Option<ControlDef>, // Endpoint viewers' commands (ie PTZ) are forwarded to
+    #[serde(default)]
+    priority: i32, // Higher priority streams are kept when a client's bandwidth runs short
+    #[serde(default)]
+    renditions: Vec<RenditionDef>, // Lower quality versions of the video, best first. Clients switch between them at keyframes
 }
 
+/**
+ * A lower quality version of a stream's video (ie a 480p or thumbnail encode of the same source),
+ * ingested as its own RTP input. Must use the same codec as the stream's video.
+ */
 #[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct RenditionDef {
+    id: String, // Name clients request the rendition by, ie "480p"
+    #[serde(flatten)]
+    track: TrackDef,
+}
+
+#[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
 pub struct TrackDef {
     port: u16,          // Stream port
     ip: Option<IpAddr>, // Optional IP to get stream from. Used for multicast addresses. Default is localhost
     codec: String,      // Codec used.
+    reorder_ms: Option<u64>, // Optional reorder buffer latency budget. Packets are passed through as received if unset.
+    ssrc: Option<u32>,       // Optional SSRC filter. Used to split a shared RTP session into streams.
+    payload_type: Option<u8>, // Optional payload type filter. Used to split a shared RTP session into streams.
+    interface: Option<String>, // Optional interface (name or address) to join multicast groups on. Default is the system's choice
+    source: Option<IpAddr>,   // Optional source address. If set, a source-specific multicast (IGMPv3 SSM) join is done
+    #[serde(default)]
+    backups: Vec<InputDef>, // Redundant inputs, in priority order. Used when the primary input above goes silent.
+    failover_ms: Option<u64>, // How long an input can be silent before failing over. Default is 2s
+    rtcp_feedback: Option<SocketAddr>, // Optional address to send keyframe requests (RTCP PLI) to, for sources that support them.
+}
+
+/**
+ * A stream's telemetry/data track. Messages are either UDP datagrams,
+ * or lines read from a TCP connection to the source.
+ */
+#[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct DataDef {
+    port: u16,
+    ip: Option<IpAddr>, // IP to listen on (UDP) or connect to (TCP). Default is localhost
+    #[serde(default)]
+    protocol: DataProtocol,
+    interface: Option<String>, // Interface to join UDP multicast groups on.
+    source: Option<IpAddr>,    // Source address for UDP source-specific multicast joins.
+    #[serde(default)]
+    replay_last: bool, // Send the most recent message to clients as they join?
+}
+
+#[derive(Hash, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DataProtocol {
+    #[default]
+    Udp,
+    Tcp,
+}
+
+/**
+ * A stream's control endpoint. Commands viewers send on the stream's control
+ * data channel are forwarded to either a UDP address or a local HTTP URL,
+ * and the endpoint's responses are relayed back to the viewer.
+ */
+#[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ControlDef {
+    udp: Option<SocketAddr>, // Send every command as a datagram. Datagrams sent back are relayed to the viewer
+    url: Option<String>,     // Or POST every command to this http:// URL. Response bodies are relayed to the viewer
+    #[serde(default)]
+    tokens: Vec<String>, // Tokens allowed to send commands. Any viewer may if empty
+    rate_limit: Option<u32>, // Max commands per second per viewer. Default is 20
+    burst: Option<u32>,      // Commands a viewer may send at once before being rate limited. Default is rate_limit
+}
+
+impl DataDef {
+    fn socket_addr(&self) -> SocketAddr {
+        let ip = self.ip.unwrap_or(IpAddr::from(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(ip, self.port)
+    }
+}
+
+/**
+ * A single RTP input of a track. The primary input is described by the
+ * TrackDef's own fields, backups are listed separately.
+ */
+#[derive(Hash, Clone, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+pub struct InputDef {
+    port: u16,
+    ip: Option<IpAddr>,
+    ssrc: Option<u32>,
+    payload_type: Option<u8>,
+    interface: Option<String>,
+    source: Option<IpAddr>,
+}
+
+impl InputDef {
+    fn socket_addr(&self) -> SocketAddr {
+        let ip = self.ip.unwrap_or(IpAddr::from(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(ip, self.port)
+    }
+
+    fn ingest_addr(&self) -> IngestAddr {
+        IngestAddr {
+            addr: self.socket_addr(),
+            interface: self.interface.clone(),
+            source: self.source,
+        }
+    }
+
+    /**
+     * Whether the passed packet belongs to this input.
+     * Inputs without SSRC/payload type filters accept everything on their socket.
+     */
+    fn accepts(&self, pkt: &Packet) -> bool {
+        self.ssrc.is_none_or(|ssrc| pkt.header.ssrc == ssrc)
+            && self
+                .payload_type
+                .is_none_or(|pt| pkt.header.payload_type == pt)
+    }
 }
 
 impl TrackDef {
@@ -61,9 +204,35 @@ impl TrackDef {
         }
     }
 
-    fn socket_addr(&self) -> SocketAddr {
-        let ip = self.ip.unwrap_or(IpAddr::from(Ipv4Addr::LOCALHOST));
-        SocketAddr::new(ip, self.port)
+    fn reorder_latency(&self) -> Option<Duration> {
+        self.reorder_ms.map(Duration::from_millis)
+    }
+
+    fn failover_timeout(&self) -> Duration {
+        Duration::from_millis(self.failover_ms.unwrap_or(DEFAULT_FAILOVER_MS))
+    }
+
+    fn clock_rate(&self) -> u32 {
+        // All currently supported codecs are video, which uses a 90kHz clock.
+        90000
+    }
+
+    /**
+     * All inputs of this track in priority order, starting with the primary.
+     */
+    fn inputs(&self) -> Vec<InputDef> {
+        let primary = InputDef {
+            port: self.port,
+            ip: self.ip,
+            ssrc: self.ssrc,
+            payload_type: self.payload_type,
+            interface: self.interface.clone(),
+            source: self.source,
+        };
+
+        std::iter::once(primary)
+            .chain(self.backups.iter().cloned())
+            .collect()
     }
 
     fn is_keyframe(&self, pkt: &Packet) -> bool {
@@ -128,16 +297,32 @@ impl TrackDef {
 // https://jsfiddle.net/xq6eua2k/1/
 #[tokio::main]
 async fn main() {
-    //let opt = Opt::from_args();
+    let opt = Opt::from_args();
+
+    let config: Config = match opt.config {
+        Some(path) => {
+            let contents = fs::read_to_string(&path).expect("Couldn't read config file");
+            serde_json::from_str(&contents).expect("Couldn't parse config file")
+        }
+        None => Config::default(),
+    };
+
+    // The test streams are on separate ports. ffmpeg sends both codecs with the same
+    // payload type, so they couldn't be told apart on a shared one.
     let test_stream = StreamDef {
         id: "test_stream".to_string(),
         default: true,
         video: Some(TrackDef {
             codec: "vp8".to_string(),
-            port: 5002,
+            port: 5004,
             ip: Some("239.7.69.7".parse::<IpAddr>().unwrap()),
+            ..Default::default()
         }),
         audio: None,
+        data: None,
+        control: None,
+        priority: 0,
+        renditions: vec![],
     };
 
     let test_stream2 = StreamDef {
@@ -147,18 +332,43 @@ async fn main() {
             codec: "h264".to_string(),
             port: 5002,
             ip: Some("239.7.69.7".parse::<IpAddr>().unwrap()),
+            ..Default::default()
         }),
         audio: None,
+        data: None,
+        control: None,
+        priority: 0,
+        renditions: vec![],
     };
+    // Fall back to the test streams if the config doesn't define any
+    let streams = if config.streams.is_empty() {
+        vec![test_stream, test_stream2]
+    } else {
+        config.streams
+    };
+
     let mut sm = StreamManager::new();
-    
-    sm.create_stream(test_stream);
-    sm.create_stream(test_stream2);
 
+    for s in streams {
+        sm.create_stream(s).expect("Failed to create stream");
+    }
+
+    let c = Arc::new(
+        app_controller::AppController::new(sm, config.rtc, config.limits)
+            .expect("Failed to initialize WebRTC"),
+    );
 
-    let c = Arc::new(app_controller::AppController::new(sm));
+    let server = server::init(
+        c.clone(),
+        tokio::runtime::Handle::current(),
+        config.admin_token,
+    );
 
-    server::init(c, tokio::runtime::Handle::current())
-        .join()
-        .unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {
+            println!("Shutting down");
+            c.shutdown().await;
+        }
+        _ = tokio::task::spawn_blocking(move || server.join().unwrap()) => {}
+    }
 }
@@ -1,17 +1,24 @@
 use socket2::{Domain, Protocol, Socket, Type};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use webrtc::util::ifaces::ifaces;
 
 /**
  * Automatically binds to the passed address, depending on what it is.
- * If the passed addr is multicast, it is joined.
+ * If the passed addr is multicast, it is joined on the passed interface
+ * (or the system default if none is passed). If a source is passed,
+ * a source-specific (SSM) join is done instead.
  * If the passed addr is not multicast, it is bound.
  *
  * All bound sockets are reusable.
  */
-pub fn listen_udp(addr: &SocketAddr) -> Result<std::net::UdpSocket, io::Error> {
+pub fn listen_udp(
+    addr: &SocketAddr,
+    interface: Option<&str>,
+    source: Option<IpAddr>,
+) -> Result<std::net::UdpSocket, io::Error> {
     if addr.ip().is_multicast() {
-        join_multicast(addr)
+        join_multicast(addr, interface, source)
     } else {
         bind_udp(addr)
     }
@@ -21,28 +28,26 @@ pub fn listen_udp(addr: &SocketAddr) -> Result<std::net::UdpSocket, io::Error> {
  * Binds a non-multicast UDP address, with reuseaddr set.
  */
 pub fn bind_udp(addr: &SocketAddr) -> Result<std::net::UdpSocket, io::Error> {
-    let socket =
-        match addr.ip() {
-            IpAddr::V4(ref _mdns_v4) => Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
-                .expect("ipv4 dgram socket"),
-
-            IpAddr::V6(ref _mdns_v6) => Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
-                .expect("ipv6 dgram socket"),
-        };
+    let socket = match addr.ip() {
+        IpAddr::V4(ref _mdns_v4) => Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?,
+        IpAddr::V6(ref _mdns_v6) => Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?,
+    };
 
-    socket.set_nonblocking(true).expect("nonblocking Error");
-    socket.set_reuse_address(true).expect("reuse addr Error");
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
 
-    socket
-        .bind(&socket2::SockAddr::from(addr.clone()))
-        .expect("bind error");
+    socket.bind(&socket2::SockAddr::from(*addr))?;
 
     let std_sock: std::net::UdpSocket = socket.into();
     Ok(std_sock)
 }
 
 /// Returns a socket joined to the multicast address
-pub fn join_multicast(multicast_addr: &SocketAddr) -> Result<std::net::UdpSocket, io::Error> {
+pub fn join_multicast(
+    multicast_addr: &SocketAddr,
+    interface: Option<&str>,
+    source: Option<IpAddr>,
+) -> Result<std::net::UdpSocket, io::Error> {
     let ip_addr = multicast_addr.ip();
     if !ip_addr.is_multicast() {
         return Err(io::Error::new(
@@ -53,35 +58,96 @@ pub fn join_multicast(multicast_addr: &SocketAddr) -> Result<std::net::UdpSocket
 
     let socket = match ip_addr {
         IpAddr::V4(ref mdns_v4) => {
-            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
-                .expect("ipv4 dgram socket");
-            socket
-                .join_multicast_v4(mdns_v4, &Ipv4Addr::new(0, 0, 0, 0))
-                .expect("join_multicast_v4");
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            let iface = match interface {
+                Some(name) => interface_v4(name)?,
+                None => Ipv4Addr::new(0, 0, 0, 0),
+            };
+
+            match source {
+                Some(IpAddr::V4(ref source_v4)) => {
+                    socket.join_ssm_v4(source_v4, mdns_v4, &iface)?
+                }
+                Some(IpAddr::V6(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("SSM source for {} must be an IPv4 address", ip_addr),
+                    ))
+                }
+                None => socket.join_multicast_v4(mdns_v4, &iface)?,
+            }
             socket
         }
         IpAddr::V6(ref mdns_v6) => {
-            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
-                .expect("ipv6 dgram socket");
+            let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+            let iface = match interface {
+                Some(name) => interface_v6(name)?,
+                None => 0,
+            };
+
+            if source.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "source-specific multicast is only supported for IPv4",
+                ));
+            }
 
             socket.set_only_v6(true)?;
-            socket
-                .join_multicast_v6(mdns_v6, 0)
-                .expect("join_multicast_v6");
+            socket.join_multicast_v6(mdns_v6, iface)?;
             socket
         }
     };
 
-    socket.set_nonblocking(true).expect("nonblocking Error");
-    socket.set_reuse_address(true).expect("reuse addr Error");
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
     #[cfg(unix)] // this is currently restricted to Unix's in socket2
-    socket.set_reuse_port(true).expect("reuse port Error");
-    bind_multicast(&socket, &multicast_addr).expect("bind Error");
+    socket.set_reuse_port(true)?;
+    bind_multicast(&socket, multicast_addr)?;
 
     let udp: std::net::UdpSocket = socket.into();
     Ok(udp)
 }
 
+/**
+ * Resolves the passed interface to the IPv4 address used for joining multicast groups.
+ * Accepts either an interface address (ie "192.168.1.10") or name (ie "eth0").
+ */
+fn interface_v4(interface: &str) -> io::Result<Ipv4Addr> {
+    if let Ok(addr) = interface.parse::<Ipv4Addr>() {
+        return Ok(addr);
+    }
+
+    ifaces()?
+        .into_iter()
+        .filter(|i| i.name == interface)
+        .find_map(|i| match i.addr.map(|a| a.ip()) {
+            Some(IpAddr::V4(addr)) => Some(addr),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no IPv4 address found for interface {}", interface),
+            )
+        })
+}
+
+/**
+ * Resolves the passed interface to the index used for joining IPv6 multicast groups.
+ * Only numeric interface indices are supported.
+ */
+fn interface_v6(interface: &str) -> io::Result<u32> {
+    interface.parse::<u32>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "IPv6 multicast interfaces must be given as an interface index, got {}",
+                interface
+            ),
+        )
+    })
+}
+
 #[cfg(windows)]
 fn bind_multicast(socket: &Socket, addr: &SocketAddr) -> io::Result<()> {
     let addr = match *addr {
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// Window over which rates are averaged.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/**
+ * Counts something (ie packets or bytes), and averages it per second over fixed windows.
+ * The rate is updated once a window has elapsed, so it trails by up to a window.
+ */
+pub struct RateCounter {
+    window_start: Instant,
+    window_count: u64,
+    per_sec: f64,
+}
+
+impl RateCounter {
+    pub fn new() -> RateCounter {
+        RateCounter {
+            window_start: Instant::now(),
+            window_count: 0,
+            per_sec: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, n: u64, now: Instant) {
+        self.roll_window(now);
+        self.window_count += n;
+    }
+
+    pub fn per_sec(&mut self, now: Instant) -> f64 {
+        self.roll_window(now);
+        self.per_sec
+    }
+
+    /**
+     * Updates the rate once the current window has elapsed.
+     */
+    fn roll_window(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        self.per_sec = self.window_count as f64 / elapsed.as_secs_f64();
+        self.window_start = now;
+        self.window_count = 0;
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::time::Instant;
+use webrtc::rtp::packet::Packet;
+
+// Upper bound on held packets. If a gap is held open this long, something
+// is very wrong upstream and it's better to flush than to grow unbounded.
+const MAX_HELD_PACKETS: usize = 1024;
+
+// How many recently skipped sequence numbers to remember, so packets that
+// show up after being declared lost can be told apart from duplicates.
+const LOST_HISTORY: usize = 512;
+
+// A sequence number further than this from the highest seen is taken as the source
+// restarting (or jumping), rather than loss or reordering, and the buffer resyncs to it.
+const RESYNC_THRESHOLD: u64 = MAX_HELD_PACKETS as u64 / 2;
+
+#[derive(Default, Clone, Copy, Debug, Serialize)]
+pub struct ReorderStats {
+    received: u64,
+    lost: u64,
+    duplicates: u64,
+    reordered: u64,
+    late: u64,
+    resyncs: u64,
+}
+
+/**
+ * Sequence-number keyed reorder (jitter) buffer for incoming RTP.
+ * Packets are held until the sequence is contiguous, or until the oldest held
+ * packet has waited longer than the latency budget. In the latter case the
+ * gap is declared lost and skipped.
+ */
+pub struct ReorderBuffer {
+    latency: Duration,
+    held: BTreeMap<u64, (Instant, Arc<Packet>)>,
+
+    // Extended (unwrapped) sequence number of the next packet to be released.
+    next_seq: Option<u64>,
+    // Highest extended sequence number seen. Used to unwrap incoming seqs.
+    highest_seq: u64,
+    ssrc: Option<u32>,
+
+    // Packets from before a resync, released ahead of anything else.
+    flushed: Vec<Arc<Packet>>,
+    recently_lost: VecDeque<u64>,
+    stats: ReorderStats,
+}
+
+impl ReorderBuffer {
+    pub fn new(latency: Duration) -> ReorderBuffer {
+        ReorderBuffer {
+            latency,
+            held: BTreeMap::new(),
+            next_seq: None,
+            highest_seq: 0,
+            ssrc: None,
+            flushed: Vec::new(),
+            recently_lost: VecDeque::new(),
+            stats: ReorderStats::default(),
+        }
+    }
+
+    /**
+     * Unwraps a 16-bit RTP sequence number into a monotonically increasing u64,
+     * picking whichever roll-over cycle puts it closest to the highest seq seen.
+     */
+    fn extend_seq(&self, seq: u16) -> u64 {
+        if self.next_seq.is_none() {
+            // Start well above zero so early packets from the "previous" cycle can't underflow.
+            return (1 << 32) | seq as u64;
+        }
+
+        let candidate = (self.highest_seq & !0xFFFF) | seq as u64;
+        if candidate + 0x8000 < self.highest_seq {
+            candidate + 0x10000
+        } else if candidate > self.highest_seq + 0x8000 {
+            candidate - 0x10000
+        } else {
+            candidate
+        }
+    }
+
+    /**
+     * Adds a freshly received packet to the buffer.
+     * Duplicates and packets that arrive after their slot was released are dropped.
+     */
+    pub fn push(&mut self, pkt: Arc<Packet>, now: Instant) {
+        self.stats.received += 1;
+
+        let ssrc_changed = self.ssrc.is_some_and(|ssrc| ssrc != pkt.header.ssrc);
+        self.ssrc = Some(pkt.header.ssrc);
+
+        let mut ext = self.extend_seq(pkt.header.sequence_number);
+        if ssrc_changed
+            || (self.next_seq.is_some() && ext.abs_diff(self.highest_seq) > RESYNC_THRESHOLD)
+        {
+            self.resync();
+            ext = self.extend_seq(pkt.header.sequence_number);
+        }
+        let next_seq = *self.next_seq.get_or_insert(ext);
+
+        if ext < next_seq {
+            if self.recently_lost.contains(&ext) {
+                self.stats.late += 1;
+            } else {
+                self.stats.duplicates += 1;
+            }
+            return;
+        }
+
+        if self.held.contains_key(&ext) {
+            self.stats.duplicates += 1;
+            return;
+        }
+
+        if ext < self.highest_seq {
+            self.stats.reordered += 1;
+        }
+        self.highest_seq = self.highest_seq.max(ext);
+
+        self.held.insert(ext, (now, pkt));
+    }
+
+    /**
+     * Starts over from the next packet pushed. Whatever is held is flushed out in order,
+     * since a restarted source's sequence numbers can't be compared against the old ones.
+     */
+    fn resync(&mut self) {
+        let held = std::mem::take(&mut self.held);
+        self.flushed.extend(held.into_values().map(|(_, pkt)| pkt));
+        self.next_seq = None;
+        self.highest_seq = 0;
+        self.recently_lost.clear();
+        self.stats.resyncs += 1;
+    }
+
+    /**
+     * Releases every packet that is ready to go out, in sequence order.
+     * A gap is only skipped once the packet waiting behind it has exceeded
+     * the latency budget (or the buffer is full).
+     */
+    pub fn pop_ready(&mut self, now: Instant) -> Vec<Arc<Packet>> {
+        let mut ready = std::mem::take(&mut self.flushed);
+
+        while let Some((&seq, &(arrived, _))) = self.held.iter().next() {
+            let next_seq = self.next_seq.unwrap_or(seq);
+
+            if seq != next_seq {
+                let expired = now.duration_since(arrived) >= self.latency;
+                if !expired && self.held.len() < MAX_HELD_PACKETS {
+                    break;
+                }
+
+                // Give up on the gap.
+                self.stats.lost += seq - next_seq;
+                for lost in next_seq..seq {
+                    if self.recently_lost.len() == LOST_HISTORY {
+                        self.recently_lost.pop_front();
+                    }
+                    self.recently_lost.push_back(lost);
+                }
+            }
+
+            let (_, pkt) = self.held.remove(&seq).unwrap();
+            self.next_seq = Some(seq + 1);
+            ready.push(pkt);
+        }
+
+        ready
+    }
+
+    /**
+     * The instant at which the currently held gap (if any) expires.
+     * The RTP reader sleeps until this point when no new packets arrive.
+     * Only the lowest held packet is released on expiry, so it alone sets the deadline.
+     */
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.held
+            .values()
+            .next()
+            .map(|(arrived, _)| *arrived + self.latency)
+    }
+
+    pub fn stats(&self) -> ReorderStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc::rtp::header::Header;
+
+    const LATENCY: Duration = Duration::from_millis(100);
+
+    fn pkt(ssrc: u32, seq: u16) -> Arc<Packet> {
+        Arc::new(Packet {
+            header: Header {
+                ssrc,
+                sequence_number: seq,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn seqs(pkts: Vec<Arc<Packet>>) -> Vec<u16> {
+        pkts.iter().map(|p| p.header.sequence_number).collect()
+    }
+
+    #[test]
+    fn reorders_within_latency() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        buf.push(pkt(1, 10), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [10]);
+
+        buf.push(pkt(1, 12), now);
+        buf.push(pkt(1, 13), now);
+        assert!(buf.pop_ready(now).is_empty());
+
+        buf.push(pkt(1, 11), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [11, 12, 13]);
+        assert_eq!(buf.stats().reordered, 1);
+        assert_eq!(buf.stats().lost, 0);
+    }
+
+    #[test]
+    fn skips_expired_gap() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        buf.push(pkt(1, 10), now);
+        buf.push(pkt(1, 12), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [10]);
+        assert_eq!(seqs(buf.pop_ready(now + LATENCY)), [12]);
+        assert_eq!(buf.stats().lost, 1);
+
+        // The skipped packet showing up afterwards is late, not a duplicate.
+        buf.push(pkt(1, 11), now + LATENCY);
+        assert!(buf.pop_ready(now + LATENCY).is_empty());
+        assert_eq!(buf.stats().late, 1);
+        assert_eq!(buf.stats().duplicates, 0);
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        buf.push(pkt(1, 10), now);
+        buf.push(pkt(1, 12), now);
+        buf.push(pkt(1, 12), now); // While held
+        assert_eq!(seqs(buf.pop_ready(now)), [10]);
+        buf.push(pkt(1, 10), now); // After release
+        buf.push(pkt(1, 11), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [11, 12]);
+        assert_eq!(buf.stats().duplicates, 2);
+    }
+
+    #[test]
+    fn unwraps_sequence_rollover() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        buf.push(pkt(1, 65534), now);
+        buf.push(pkt(1, 0), now);
+        buf.push(pkt(1, 65535), now);
+        buf.push(pkt(1, 1), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [65534, 65535, 0, 1]);
+        assert_eq!(buf.stats().resyncs, 0);
+    }
+
+    #[test]
+    fn resyncs_on_ssrc_change() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        buf.push(pkt(1, 1000), now);
+        buf.push(pkt(1, 1002), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [1000]);
+
+        // The held packet is flushed ahead of the new source's, without waiting on the gap.
+        buf.push(pkt(2, 1001), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [1002, 1001]);
+        buf.push(pkt(2, 1002), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [1002]);
+        assert_eq!(buf.stats().resyncs, 1);
+    }
+
+    #[test]
+    fn resyncs_on_restart() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        for seq in 30000..30010 {
+            buf.push(pkt(1, seq), now);
+        }
+        assert_eq!(buf.pop_ready(now).len(), 10);
+
+        // Same SSRC, but counting from somewhere else entirely.
+        buf.push(pkt(1, 5), now);
+        buf.push(pkt(1, 6), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [5, 6]);
+        assert_eq!(buf.stats().resyncs, 1);
+        assert_eq!(buf.stats().duplicates, 0);
+    }
+
+    #[test]
+    fn deadline_follows_lowest_held() {
+        let mut buf = ReorderBuffer::new(LATENCY);
+        let now = Instant::now();
+
+        buf.push(pkt(1, 10), now);
+        assert_eq!(seqs(buf.pop_ready(now)), [10]);
+
+        // 11 is missing. 13 arrives before 12, so 12's gap expires later than 13's would.
+        let later = now + LATENCY * 4 / 5;
+        buf.push(pkt(1, 13), now);
+        buf.push(pkt(1, 12), later);
+
+        let at = now + LATENCY;
+        assert!(buf.pop_ready(at).is_empty());
+        let deadline = buf.next_deadline().unwrap();
+        assert!(deadline > at);
+
+        assert_eq!(seqs(buf.pop_ready(deadline)), [12, 13]);
+        assert_eq!(buf.next_deadline(), None);
+    }
+}
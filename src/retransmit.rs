@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::time::Instant;
+use webrtc::rtp::packet::Packet;
+
+use crate::rate_counter::RateCounter;
+
+// Number of recent packets kept per track for retransmission.
+// Must comfortably exceed a round trip's worth of packets at the highest bitrate.
+pub const CACHE_SIZE: usize = 2048;
+
+/**
+ * Recently distributed packets of an RtpTrack, keyed by sequence number.
+ * Shared by every client viewing the track, so NACKed packets can be re-sent
+ * without each client's peer connection keeping its own history.
+ */
+pub struct RetransmitCache {
+    packets: Vec<Option<Arc<Packet>>>,
+}
+
+impl RetransmitCache {
+    pub fn new() -> RetransmitCache {
+        RetransmitCache {
+            packets: vec![None; CACHE_SIZE],
+        }
+    }
+
+    pub fn insert(&mut self, pkt: Arc<Packet>) {
+        let idx = pkt.header.sequence_number as usize % CACHE_SIZE;
+        self.packets[idx] = Some(pkt);
+    }
+
+    /**
+     * Returns the packet with the passed sequence number, if it's still cached.
+     */
+    pub fn get(&self, seq: u16) -> Option<Arc<Packet>> {
+        self.packets[seq as usize % CACHE_SIZE]
+            .as_ref()
+            .filter(|p| p.header.sequence_number == seq)
+            .cloned()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct NackStats {
+    nacks: u64,         // NACK messages received
+    requested: u64,     // Packets requested by NACKs
+    retransmitted: u64, // Requested packets that were re-sent
+    missed: u64,        // Requested packets no longer in the cache
+    requested_per_sec: f64,
+}
+
+/**
+ * NACK bookkeeping for a client.
+ */
+pub struct NackCounter {
+    stats: NackStats,
+    requested_rate: RateCounter,
+}
+
+impl NackCounter {
+    pub fn new() -> NackCounter {
+        NackCounter {
+            stats: NackStats::default(),
+            requested_rate: RateCounter::new(),
+        }
+    }
+
+    pub fn record(&mut self, requested: usize, retransmitted: usize, now: Instant) {
+        self.stats.nacks += 1;
+        self.stats.requested += requested as u64;
+        self.stats.retransmitted += retransmitted as u64;
+        self.stats.missed += (requested - retransmitted) as u64;
+        self.requested_rate.add(requested as u64, now);
+    }
+
+    pub fn stats(&mut self, now: Instant) -> NackStats {
+        self.stats.requested_per_sec = self.requested_rate.per_sec(now);
+        self.stats
+    }
+}
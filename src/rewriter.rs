@@ -0,0 +1,203 @@
+// Sequence number and timestamp rewriting, for splicing separate runs of RTP packets
+// (ie replays from a fast-start buffer, or another input's packets) into one continuous stream.
+use std::sync::Arc;
+
+use tokio::time::Instant;
+use webrtc::rtp::packet::Packet;
+
+/**
+ * Sequence number, timestamp and send time of a rewritten packet.
+ */
+pub type SentPosition = (u16, u32, Instant);
+
+/**
+ * Offsets the sequence numbers and timestamps of packets. After a restart, offsets
+ * are picked so the next packet directly follows the last one written, with its
+ * timestamp advanced by however long it's been since.
+ */
+pub struct Rewriter {
+    clock_rate: u32,
+    seq_offset: u16,
+    ts_offset: u32,
+    realign: bool,
+
+    // Rewritten sequence number of the first packet written with the current offsets.
+    epoch_start: Option<u16>,
+
+    // The last written packet.
+    last_out: Option<SentPosition>,
+}
+
+impl Rewriter {
+    pub fn new(clock_rate: u32) -> Rewriter {
+        Rewriter {
+            clock_rate,
+            seq_offset: 0,
+            ts_offset: 0,
+            realign: false,
+            epoch_start: None,
+            last_out: None,
+        }
+    }
+
+    /**
+     * Makes the next packet directly follow the last one written.
+     */
+    pub fn restart(&mut self) {
+        self.realign = true;
+    }
+
+    /**
+     * Makes the next packet directly follow the passed one (ie written by another rewriter).
+     */
+    pub fn continue_after(&mut self, last: SentPosition) {
+        self.last_out = Some(last);
+        self.realign = true;
+    }
+
+    pub fn last_out(&self) -> Option<SentPosition> {
+        self.last_out
+    }
+
+    pub fn rewrite(&mut self, pkt: &Arc<Packet>, now: Instant) -> Arc<Packet> {
+        if self.realign {
+            self.realign = false;
+            if let Some((seq, ts, at)) = self.last_out {
+                let elapsed = now.duration_since(at).as_secs_f64();
+                let ts_gap = ((elapsed * self.clock_rate as f64) as u32).max(1);
+
+                self.seq_offset = seq.wrapping_add(1).wrapping_sub(pkt.header.sequence_number);
+                self.ts_offset = ts.wrapping_add(ts_gap).wrapping_sub(pkt.header.timestamp);
+            }
+            self.epoch_start = None;
+        }
+
+        let p = self.apply(pkt);
+
+        self.epoch_start.get_or_insert(p.header.sequence_number);
+        self.last_out = Some((p.header.sequence_number, p.header.timestamp, now));
+        p
+    }
+
+    /**
+     * Rewrites a packet with the current offsets, without advancing any state.
+     */
+    pub fn apply(&self, pkt: &Arc<Packet>) -> Arc<Packet> {
+        if self.seq_offset == 0 && self.ts_offset == 0 {
+            return pkt.clone();
+        }
+
+        let mut p = (**pkt).clone();
+        p.header.sequence_number = p.header.sequence_number.wrapping_add(self.seq_offset);
+        p.header.timestamp = p.header.timestamp.wrapping_add(self.ts_offset);
+        Arc::new(p)
+    }
+
+    /**
+     * Maps a rewritten sequence number back to the original one.
+     * Only packets written since the last restart can be mapped, as older ones used other offsets.
+     */
+    pub fn original_seq(&self, seq: u16) -> Option<u16> {
+        let start = self.epoch_start?;
+        let (last, _, _) = self.last_out?;
+
+        if last.wrapping_sub(seq) <= last.wrapping_sub(start) {
+            Some(seq.wrapping_sub(self.seq_offset))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use webrtc::rtp::header::Header;
+
+    const CLOCK_RATE: u32 = 90000;
+
+    fn pkt(seq: u16, ts: u32) -> Arc<Packet> {
+        Arc::new(Packet {
+            header: Header {
+                sequence_number: seq,
+                timestamp: ts,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn passes_through_until_restarted() {
+        let mut r = Rewriter::new(CLOCK_RATE);
+        let now = Instant::now();
+
+        let p = pkt(10, 1000);
+        let out = r.rewrite(&p, now);
+        assert!(Arc::ptr_eq(&p, &out));
+        assert_eq!(r.last_out(), Some((10, 1000, now)));
+        assert_eq!(r.original_seq(10), Some(10));
+    }
+
+    #[test]
+    fn restart_continues_from_last_written() {
+        let mut r = Rewriter::new(CLOCK_RATE);
+        let start = Instant::now();
+
+        r.rewrite(&pkt(10, 1000), start);
+        r.rewrite(&pkt(11, 4000), start);
+
+        // Replaying from an older packet, half a second later.
+        r.restart();
+        let later = start + Duration::from_millis(500);
+        let out = r.rewrite(&pkt(5, 100), later);
+        assert_eq!(out.header.sequence_number, 12);
+        assert_eq!(out.header.timestamp, 4000 + CLOCK_RATE / 2);
+
+        let next = r.rewrite(&pkt(6, 3100), later);
+        assert_eq!(next.header.sequence_number, 13);
+        assert_eq!(next.header.timestamp, 4000 + CLOCK_RATE / 2 + 3000);
+    }
+
+    #[test]
+    fn timestamps_always_advance() {
+        let mut r = Rewriter::new(CLOCK_RATE);
+        let now = Instant::now();
+
+        r.rewrite(&pkt(10, 1000), now);
+        r.restart();
+        let out = r.rewrite(&pkt(20, 1000), now);
+        assert_eq!(out.header.sequence_number, 11);
+        assert_eq!(out.header.timestamp, 1001);
+    }
+
+    #[test]
+    fn continues_after_another_rewriter() {
+        let now = Instant::now();
+        let mut r = Rewriter::new(CLOCK_RATE);
+        r.continue_after((65535, u32::MAX, now));
+
+        let out = r.rewrite(&pkt(300, 0), now);
+        assert_eq!(out.header.sequence_number, 0);
+        assert_eq!(out.header.timestamp, 0);
+    }
+
+    #[test]
+    fn maps_only_current_epoch_back() {
+        let mut r = Rewriter::new(CLOCK_RATE);
+        let now = Instant::now();
+
+        r.rewrite(&pkt(10, 1000), now);
+        r.rewrite(&pkt(11, 1000), now);
+        r.restart();
+        r.rewrite(&pkt(500, 2000), now); // Written as 12
+        r.rewrite(&pkt(501, 2000), now); // Written as 13
+
+        assert_eq!(r.original_seq(12), Some(500));
+        assert_eq!(r.original_seq(13), Some(501));
+        assert_eq!(r.original_seq(11), None); // Sent with the previous offsets
+        assert_eq!(r.original_seq(14), None); // Not sent yet
+        assert_eq!(r.apply(&pkt(502, 2000)).header.sequence_number, 14);
+    }
+}
@@ -0,0 +1,276 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use webrtc::{
+    api::{
+        interceptor_registry::{configure_rtcp_reports, configure_twcc},
+        media_engine::MediaEngine,
+        setting_engine::SettingEngine,
+        APIBuilder, API,
+    },
+    ice::{
+        udp_mux::{UDPMuxDefault, UDPMuxParams},
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
+    interceptor::registry::Registry,
+};
+
+// Default lifetime of generated TURN REST API credentials.
+const DEFAULT_TURN_TTL_S: u64 = 24 * 60 * 60;
+
+// Default time a disconnected client has to reconnect (ie with an ICE restart).
+const DEFAULT_DISCONNECTED_GRACE_S: u64 = 15;
+
+/**
+ * A STUN/TURN server. TURN credentials are either static (username + credential)
+ * or time-limited, generated from a secret shared with the TURN server
+ * (the "TURN REST API" scheme supported by coturn's use-auth-secret).
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct IceServerDef {
+    urls: Vec<String>,
+    username: Option<String>, // Static TURN username, or the user part of generated usernames.
+    credential: Option<String>, // Static TURN password.
+    secret: Option<String>,   // Shared secret for time-limited TURN credentials.
+    ttl_s: Option<u64>,       // Lifetime of time-limited TURN credentials. Default is 24h
+}
+
+/**
+ * ICE server entry in the shape browsers expect for RTCPeerConnection's `iceServers`.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct BrowserIceServer {
+    urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+}
+
+/**
+ * WebRTC/ICE settings shared by every client's peer connection.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RtcConfig {
+    #[serde(default)]
+    ice_servers: Vec<IceServerDef>,
+    #[serde(default)]
+    nat_1to1_ips: Vec<String>, // Public IPs to advertise instead of local host candidates (ie behind a 1:1 NAT)
+    udp_port_min: Option<u16>, // Restricts the ephemeral UDP ports used for ICE
+    udp_port_max: Option<u16>,
+    udp_mux_port: Option<u16>, // If set, every client shares this single UDP port. Overrides the port range
+    #[serde(default)]
+    interfaces: Vec<String>, // Network interfaces to gather candidates on. Default is all
+    disconnected_grace_s: Option<u64>, // How long disconnected clients are kept for. Default is 15s
+}
+
+impl IceServerDef {
+    /**
+     * Resolves this server's credentials, generating time-limited ones if a secret is set.
+     */
+    fn credentials(&self) -> (Option<String>, Option<String>) {
+        self.credentials_at(SystemTime::now())
+    }
+
+    fn credentials_at(&self, now: SystemTime) -> (Option<String>, Option<String>) {
+        let secret = match self.secret {
+            Some(ref s) => s,
+            None => return (self.username.clone(), self.credential.clone()),
+        };
+
+        let expiry = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+            + self.ttl_s.unwrap_or(DEFAULT_TURN_TTL_S);
+
+        // username = "<expiry timestamp>:<user>", password = base64(hmac-sha1(secret, username))
+        let username = match self.username {
+            Some(ref user) => format!("{}:{}", expiry, user),
+            None => expiry.to_string(),
+        };
+
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+        let tag = hmac::sign(&key, username.as_bytes());
+
+        (Some(username), Some(base64::encode(tag.as_ref())))
+    }
+}
+
+impl RtcConfig {
+    /**
+     * ICE servers for a server-side peer connection.
+     */
+    pub fn ice_servers(&self) -> Vec<RTCIceServer> {
+        self.ice_servers
+            .iter()
+            .map(|s| {
+                let (username, credential) = s.credentials();
+                RTCIceServer {
+                    urls: s.urls.clone(),
+                    username: username.unwrap_or_default(),
+                    credential: credential.unwrap_or_default(),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * The same ICE servers, for handing to the browser so both sides agree.
+     */
+    pub fn browser_ice_servers(&self) -> Vec<BrowserIceServer> {
+        self.ice_servers
+            .iter()
+            .map(|s| {
+                let (username, credential) = s.credentials();
+                BrowserIceServer {
+                    urls: s.urls.clone(),
+                    username,
+                    credential,
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * How long a disconnected client is kept before being discarded,
+     * giving it a chance to recover or restart ICE.
+     */
+    pub fn disconnected_grace(&self) -> Duration {
+        Duration::from_secs(
+            self.disconnected_grace_s
+                .unwrap_or(DEFAULT_DISCONNECTED_GRACE_S),
+        )
+    }
+
+    /**
+     * Builds the webrtc-rs API shared by every client's peer connection.
+     * Media engine, interceptors and the UDP mux (if enabled) are set up once here.
+     */
+    pub fn build_api(&self) -> Result<API> {
+        // webrtc-rs boilerplate. See their examples for more info
+        let mut m = MediaEngine::default();
+        m.register_default_codecs()?;
+
+        // webrtc-rs's default interceptors, minus the NACK responder. Rather than every
+        // client keeping its own copy of sent packets, NACKs are answered from each
+        // track's shared retransmission cache.
+        let mut registry = Registry::new();
+        registry = configure_rtcp_reports(registry);
+        // Also registers transport-cc feedback for bandwidth estimation, which the
+        // default codecs don't include (unlike NACK and REMB).
+        registry = configure_twcc(registry, &mut m)?;
+
+        Ok(APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .with_setting_engine(self.setting_engine()?)
+            .build())
+    }
+
+    /**
+     * Builds the webrtc-rs SettingEngine applying NAT mapping, UDP port settings and interface filters.
+     */
+    fn setting_engine(&self) -> Result<SettingEngine> {
+        let mut s = SettingEngine::default();
+
+        if !self.nat_1to1_ips.is_empty() {
+            s.set_nat_1to1_ips(self.nat_1to1_ips.clone(), RTCIceCandidateType::Host);
+        }
+
+        if let Some(port) = self.udp_mux_port {
+            let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port);
+            let sock = std::net::UdpSocket::bind(addr)?;
+            sock.set_nonblocking(true)?;
+
+            let mux = UDPMuxDefault::new(UDPMuxParams::new(tokio::net::UdpSocket::from_std(sock)?));
+            s.set_udp_network(UDPNetwork::Muxed(mux));
+        } else if self.udp_port_min.is_some() || self.udp_port_max.is_some() {
+            let ports = EphemeralUDP::new(
+                self.udp_port_min.unwrap_or(1),
+                self.udp_port_max.unwrap_or(u16::MAX),
+            )?;
+            s.set_udp_network(UDPNetwork::Ephemeral(ports));
+        }
+
+        if !self.interfaces.is_empty() {
+            let interfaces = self.interfaces.clone();
+            s.set_interface_filter(Box::new(move |name: &str| {
+                interfaces.iter().any(|i| i == name)
+            }));
+        }
+
+        Ok(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2023-11-14T22:13:20Z
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    fn turn(username: Option<&str>, ttl_s: Option<u64>) -> IceServerDef {
+        IceServerDef {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            username: username.map(str::to_string),
+            secret: Some("north".to_string()),
+            ttl_s,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn static_credentials_pass_through() {
+        let server = IceServerDef {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            username: Some("alice".to_string()),
+            credential: Some("hunter2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            server.credentials_at(now()),
+            (Some("alice".to_string()), Some("hunter2".to_string()))
+        );
+    }
+
+    #[test]
+    fn generated_credentials_format() {
+        // Expected passwords are base64(hmac-sha1("north", username)), computed independently.
+        assert_eq!(
+            turn(Some("alice"), None).credentials_at(now()),
+            (
+                Some("1700086400:alice".to_string()),
+                Some("SXua5ne/+mDhiHTp0pQJzRO4ESg=".to_string())
+            )
+        );
+        assert_eq!(
+            turn(None, None).credentials_at(now()),
+            (
+                Some("1700086400".to_string()),
+                Some("K+RlgFj3QLvB7WAVHfjfgMgyBeA=".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn generated_credentials_expire_after_ttl() {
+        let (username, _) = turn(Some("alice"), Some(600)).credentials_at(now());
+        assert_eq!(username.unwrap(), "1700000600:alice");
+
+        // The static credential is ignored when generating.
+        let mut server = turn(None, Some(60));
+        server.credential = Some("hunter2".to_string());
+        let (username, credential) = server.credentials_at(now());
+        assert_eq!(username.unwrap(), "1700000060");
+        assert_ne!(credential.unwrap(), "hunter2");
+    }
+}
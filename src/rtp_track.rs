@@ -1,12 +1,20 @@
-use crate::net_util::listen_udp;
-use crate::{StreamDef, TrackDef};
+use crate::failover::Failover;
+use crate::ingest_health::{IngestHealth, TrackStateEvent, TrackStatus, POLL_INTERVAL};
+use crate::ingest_socket::IngestSocket;
+use crate::reorder_buffer::{ReorderBuffer, ReorderStats};
+use crate::retransmit::RetransmitCache;
+use crate::{InputDef, StreamDef, TrackDef};
 use std::collections::VecDeque;
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::sync::broadcast::{self, Receiver, Sender};
-use tokio::sync::RwLock;
+use tokio::select;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{interval, sleep_until, Instant};
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
 use webrtc::rtp::packet::Packet;
-use webrtc::util::Unmarshal;
+use webrtc::util::Marshal;
 
 // NOTE: This should probably be a VecDequeue to optimize
 // removal of old packets on new keyframe (see handle_fs_buffering)
@@ -21,9 +29,17 @@ pub struct RtpTrack {
     pub track_def: TrackDef,
     ff_packets: Arc<FastStartBuf>,
     subscriber: Receiver<Arc<Packet>>,
+    reorder_stats: Arc<RwLock<ReorderStats>>,
+    health: Arc<RwLock<IngestHealth>>,
+    last_keyframe_request: Mutex<Option<Instant>>,
+    retransmit: Arc<RwLock<RetransmitCache>>,
 }
 const MAX_PACKETS: usize = 10000;
 
+// Minimum time between keyframe requests forwarded to a track's source.
+// Every client that loses a frame sends one, and sources shouldn't be flooded.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Default, Clone, Copy)]
 pub struct StreamState {
     /**
@@ -39,91 +55,269 @@ pub struct StreamState {
  * Handles the ingestion and buffering of an RTP track.
  */
 impl RtpTrack {
-    pub fn new(track_def: &TrackDef, stream_def: &StreamDef) -> RtpTrack {
+    pub fn new(
+        track_def: &TrackDef,
+        stream_def: &StreamDef,
+        name: String, // Names the track in its state events, ie "video"
+        inputs: Vec<(InputDef, Arc<IngestSocket>)>,
+        events: Sender<TrackStateEvent>,
+    ) -> RtpTrack {
         // Distribute to feeders
         let ff_packets = Arc::new(RwLock::new(Vec::new()));
 
+        let reorder_stats = Arc::new(RwLock::new(ReorderStats::default()));
+        let health = Arc::new(RwLock::new(IngestHealth::new()));
+
         let (tx, subscriber) = broadcast::channel::<Arc<Packet>>(MAX_PACKETS);
 
-        RtpTrack::task_rtp_reader(Arc::downgrade(&ff_packets), tx, track_def.clone(), true);
+        let retransmit = Arc::new(RwLock::new(RetransmitCache::new()));
+        RtpTrack::task_retransmit_cache(Arc::downgrade(&retransmit), subscriber.resubscribe());
+
+        // Funnel all of this track's inputs into one channel, tagged with the input's index.
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        for (idx, (input, socket)) in inputs.into_iter().enumerate() {
+            RtpTrack::task_input_forwarder(idx, input, socket, input_tx.clone());
+        }
+        drop(input_tx);
+
+        RtpTrack::task_rtp_reader(
+            Arc::downgrade(&ff_packets),
+            Arc::downgrade(&reorder_stats),
+            Arc::downgrade(&health),
+            input_rx,
+            tx,
+            track_def.clone(),
+            true,
+        );
+
+        RtpTrack::task_health_monitor(
+            Arc::downgrade(&health),
+            events,
+            stream_def.id.clone(),
+            name,
+        );
 
         RtpTrack {
             ff_packets,
             stream_def: stream_def.clone(),
             track_def: track_def.clone(),
             subscriber,
+            reorder_stats,
+            health,
+            last_keyframe_request: Mutex::new(None),
+            retransmit,
         }
     }
 
     /**
-     * RTP reader task. Continuously grabs RTP packets from the IP specified in the track definition
+     * Retransmission cache task. Keeps the track's recently distributed packets
+     * around so clients' NACKs can be answered.
+     */
+    fn task_retransmit_cache(
+        cache: Weak<RwLock<RetransmitCache>>,
+        mut rx: broadcast::Receiver<Arc<Packet>>,
+    ) {
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(pkt) => match cache.upgrade() {
+                        Some(c) => c.write().await.insert(pkt),
+                        None => break,
+                    },
+                    Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /**
+     * Health monitor task. Periodically re-evaluates the track's state
+     * and emits an event whenever it changes.
+     */
+    fn task_health_monitor(
+        health: Weak<RwLock<IngestHealth>>,
+        events: Sender<TrackStateEvent>,
+        stream_id: String,
+        track: String,
+    ) {
+        tokio::spawn(async move {
+            let mut last_state = None;
+            let mut interval = interval(POLL_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let state = match health.upgrade() {
+                    Some(h) => h.read().await.state(Instant::now()),
+                    None => break,
+                };
+
+                if last_state != Some(state) {
+                    println!("Track {}/{} is now {:?}", stream_id, track, state);
+
+                    // An error just means nobody is listening for events.
+                    events
+                        .send(TrackStateEvent {
+                            stream_id: stream_id.clone(),
+                            track: track.clone(),
+                            state,
+                        })
+                        .ok();
+                    last_state = Some(state);
+                }
+            }
+        });
+    }
+
+    /**
+     * Input forwarder task. Keeps the packets on an ingest socket that match the input's
+     * SSRC/payload type filters and hands them to the track's RTP reader.
+     * Exits once the RTP reader is gone.
+     */
+    fn task_input_forwarder(
+        idx: usize,
+        input: InputDef,
+        socket: Arc<IngestSocket>,
+        tx: mpsc::UnboundedSender<(usize, Arc<Packet>)>,
+    ) {
+        tokio::spawn(async move {
+            // The socket arc is held for as long as this task runs, so the
+            // socket stays open while any track is reading from it.
+            let mut ingest = socket.subscribe();
+
+            loop {
+                match ingest.recv().await {
+                    Ok(pkt) if input.accepts(&pkt) => {
+                        if tx.send((idx, pkt)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(RecvError::Lagged(n)) => {
+                        println!("Input lagged behind ingest socket, {} packets skipped", n);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /**
+     * RTP reader task. Continuously grabs RTP packets from the track's inputs
      * and distributes them to BufferedTracks. Optionally, also handles buffering packets
      * for fast-starting new clients.
+     *
+     * Packets from redundant inputs go through Failover, which only lets the active
+     * input's packets through. If the track definition specifies a reorder latency,
+     * packets are then passed through a ReorderBuffer so they're distributed in sequence order.
      */
     fn task_rtp_reader(
         fast_start_packets: Weak<FastStartBuf>,
+        reorder_stats: Weak<RwLock<ReorderStats>>,
+        health: Weak<RwLock<IngestHealth>>,
+        mut inputs: mpsc::UnboundedReceiver<(usize, Arc<Packet>)>,
         broadcast: Sender<Arc<Packet>>,
         def: TrackDef,
         fast_start: bool,
     ) {
         tokio::spawn(async move {
             let mut stream_state = StreamState::default();
+            let mut reorder = def.reorder_latency().map(ReorderBuffer::new);
+            let mut failover = Failover::new(def.failover_timeout(), def.clock_rate());
 
-            let sock = listen_udp(&def.socket_addr()).unwrap();
-            let sock = UdpSocket::from_std(sock).unwrap();
-
-            let mut buf = vec![0u8; 1600];
             loop {
-                if let Ok((n, _)) = sock.recv_from(&mut buf).await {
-                    let trimmed = buf[..n].to_vec();
-
-                    if n > 1200 {
-                        println!(
-                            "ERROR: Received an RTP packet greater than 1200 bytes! 
-                        Make sure your format address specifies a max packet size of 1200!! 
-                        (Hint: try adding `-pkt_size 1200` to your FFMPEG command)"
-                        );
-                        break;
-                    }
+                let deadline = reorder.as_ref().and_then(|r| r.next_deadline());
 
-                    // Parse the incoming data into a Packet struct
-                    // using WebRtc-rs's unmarshal to access RTP information.
-                    let mut b: &[u8] = &trimmed;
-                    let pkt = Arc::new(Packet::unmarshal(&mut b).unwrap());
-
-                    // Handle buffering (if enabled) and exiting on main struct deletion
-                    // We use the dropping of the fast_start_packets Arc to recognize the
-                    // deletion of the parent track.
-                    match fast_start_packets.upgrade() {
-                        Some(ff) if fast_start => {
-                            RtpTrack::handle_fast_start_buffering(
-                                ff,
-                                pkt.clone(),
-                                &def,
-                                &mut stream_state,
-                            )
-                            .await;
-                        }
+                // Wait for either a new packet, or for a held reorder gap to expire.
+                let recv = select! {
+                    r = inputs.recv() => Some(r),
+                    _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => None,
+                };
 
-                        None => {
-                            println!("FF buffer gone. Exiting RTP");
-                            break;
+                let mut pkts = Vec::new();
+
+                match recv {
+                    Some(Some((idx, pkt))) => {
+                        let now = Instant::now();
+                        if let Some(pkt) = failover.process(idx, pkt, &def, now) {
+                            match health.upgrade() {
+                                Some(h) => h.write().await.record(&pkt, def.is_keyframe(&pkt), now),
+                                None => break,
+                            }
+
+                            match reorder {
+                                Some(ref mut r) => r.push(pkt, now),
+                                None => pkts.push(pkt),
+                            }
                         }
-                        _ => (),
                     }
+                    // Every input forwarder has exited
+                    Some(None) => break,
+                    None => (),
+                }
 
-                    // Broadcast the packet to listening BufferedTracks
-                    if let Err(e) = broadcast.send(pkt) {
-                        println!("BROADCAST ERR: {}", e);
+                if let Some(ref mut r) = reorder {
+                    pkts.extend(r.pop_ready(Instant::now()));
+
+                    match reorder_stats.upgrade() {
+                        Some(stats) => *stats.write().await = r.stats(),
+                        None => break,
                     }
-                } else {
-                    println!("Problem receiving from UDP socket");
+                }
+
+                if !RtpTrack::dispatch(
+                    pkts,
+                    &fast_start_packets,
+                    &broadcast,
+                    &def,
+                    &mut stream_state,
+                    fast_start,
+                )
+                .await
+                {
+                    println!("FF buffer gone. Exiting RTP");
+                    break;
                 }
             }
             println!("RTP reader exited.")
         });
     }
 
+    /**
+     * Buffers (if enabled) and broadcasts ordered packets to listening BufferedTracks.
+     * Returns false if the parent track has been deleted and the reader should exit.
+     */
+    async fn dispatch(
+        pkts: Vec<Arc<Packet>>,
+        fast_start_packets: &Weak<FastStartBuf>,
+        broadcast: &Sender<Arc<Packet>>,
+        def: &TrackDef,
+        stream_state: &mut StreamState,
+        fast_start: bool,
+    ) -> bool {
+        for pkt in pkts {
+            // Handle buffering (if enabled) and exiting on main struct deletion
+            // We use the dropping of the fast_start_packets Arc to recognize the
+            // deletion of the parent track.
+            match fast_start_packets.upgrade() {
+                Some(ff) if fast_start => {
+                    RtpTrack::handle_fast_start_buffering(ff, pkt.clone(), def, stream_state).await;
+                }
+
+                None => return false,
+                _ => (),
+            }
+
+            // Broadcast the packet to listening BufferedTracks
+            if let Err(e) = broadcast.send(pkt) {
+                println!("BROADCAST ERR: {}", e);
+            }
+        }
+
+        true
+    }
+
     /**
      * KeyFrame buffering logic.
      * Chrome seems to require TWO keyframes to being displaying video (from testing).
@@ -185,10 +379,109 @@ impl RtpTrack {
         self.ff_packets.read().await.clone()
     }
 
+    /**
+     * The fast-start buffer, starting at the most recent keyframe rather than the
+     * oldest one. Used to recover clients that lost a frame, which (unlike new clients)
+     * only need a single keyframe. Packets sharing the keyframe's timestamp
+     * (ie H264 parameter sets) are included.
+     */
+    pub async fn ff_buf_from_last_keyframe(&self) -> Vec<Arc<Packet>> {
+        let ff = self.ff_packets.read().await;
+
+        let start = match ff.iter().rposition(|p| self.track_def.is_keyframe(p)) {
+            Some(kf) => {
+                let ts = ff[kf].header.timestamp;
+                ff[..kf]
+                    .iter()
+                    .rposition(|p| p.header.timestamp != ts)
+                    .map_or(0, |i| i + 1)
+            }
+            None => 0,
+        };
+
+        ff[start..].to_vec()
+    }
+
+    /**
+     * A recently distributed packet, for retransmission.
+     */
+    pub async fn retransmit_packet(&self, seq: u16) -> Option<Arc<Packet>> {
+        self.retransmit.read().await.get(seq)
+    }
+
+    /**
+     * Asks the track's source for a new keyframe by sending it an RTCP PLI,
+     * if the track definition says where to send them. Rate limited.
+     */
+    pub async fn request_keyframe(&self) {
+        let addr = match self.track_def.rtcp_feedback {
+            Some(addr) => addr,
+            None => return,
+        };
+
+        {
+            let mut last = self.last_keyframe_request.lock().unwrap();
+            if last.is_some_and(|t| t.elapsed() < KEYFRAME_REQUEST_INTERVAL) {
+                return;
+            }
+            *last = Some(Instant::now());
+        }
+
+        // The source's SSRC, taken from the most recent packet.
+        let media_ssrc = match self.ff_packets.read().await.last() {
+            Some(p) => p.header.ssrc,
+            None => return,
+        };
+
+        let pli = PictureLossIndication {
+            sender_ssrc: 0,
+            media_ssrc,
+        };
+
+        let res = async {
+            let buf = pli.marshal()?;
+            let bind_addr = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let sock = UdpSocket::bind(bind_addr).await?;
+            sock.send_to(&buf, addr).await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(e) = res {
+            eprintln!("Failed to send keyframe request to {}: {}", addr, e);
+        }
+    }
+
+    /**
+     * Liveness info (state, rates, keyframe interval) for this track.
+     */
+    pub async fn status(&self) -> TrackStatus {
+        self.health.write().await.status(Instant::now())
+    }
+
+    /**
+     * How long until this track's next keyframe is expected, if that's known.
+     */
+    pub async fn next_keyframe_in(&self) -> Option<Duration> {
+        self.health.read().await.next_keyframe_in(Instant::now())
+    }
+
+    /**
+     * Loss/duplicate/reorder counters from this track's reorder buffer.
+     * All zero if reordering is disabled for the track.
+     */
+    pub async fn reorder_stats(&self) -> ReorderStats {
+        *self.reorder_stats.read().await
+    }
+
     /**
      * Returns a new broadcast handle that distributes this stream's RTP packets
      * as they're received. Should be used to distribute a stream's packets
-     * to a client. 
+     * to a client.
      */
     pub fn subscribe(&self) -> broadcast::Receiver<Arc<Packet>> {
         self.subscriber.resubscribe()
@@ -2,24 +2,42 @@ use std::{
     io::Read,
     path::{Path, PathBuf},
     sync::Arc,
-    time,
+    time::Duration,
 };
 
 // Powers the internal server
-use crate::{app_controller::AppController, stats::SystemStatusReader};
+use crate::{
+    app_controller::{AppController, ClientLimitReached},
+    stats::SystemStatusReader,
+    whep,
+};
 use anyhow::{anyhow, bail, Result};
-use rouille::{extension_to_mime, router, Request, Response};
+use ring::constant_time::verify_slices_are_equal;
+use rouille::{extension_to_mime, router, websocket, Request, Response};
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
 use sysinfo::{System, SystemExt};
 use tokio::runtime::Handle;
-use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use tokio::sync::broadcast::{self, error::RecvError};
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::sdp::session_description::RTCSessionDescription,
+};
 
 #[derive(Deserialize)]
 struct SignallingRequest {
     stream_ids: Vec<String>,
     uid: String,
     offer: RTCSessionDescription,
+    #[serde(default)]
+    trickle: bool, // If set, the answer is returned before ICE gathering completes.
+    token: Option<String>, // Authorizes commands sent on streams' control channels.
+}
+
+#[derive(Deserialize)]
+struct IceCandidateRequest {
+    uid: String,
+    candidate: RTCIceCandidateInit,
 }
 
 #[derive(Deserialize)]
@@ -28,12 +46,37 @@ struct SyncRequest {
     uid: String,
 }
 
+#[derive(Deserialize)]
+struct RenditionRequest {
+    uid: String,
+    stream_id: String,
+    rendition: Option<String>, // Null lets the server pick, depending on the client's bandwidth.
+}
+
+#[derive(Deserialize)]
+struct ThumbnailRequest {
+    uid: String,
+    stream_ids: Vec<String>,
+    interval_s: Option<f64>, // Min seconds between keyframes sent. Null goes back to full rate.
+}
+
+#[derive(Deserialize)]
+struct KickRequest {
+    uid: String,
+}
+
 #[derive(RustEmbed)]
 #[folder = "frontend/dist/"]
 struct Assets;
 
-pub fn init(c: Arc<AppController>, rt: Handle) -> std::thread::JoinHandle<()> {
+pub fn init(
+    c: Arc<AppController>,
+    rt: Handle,
+    admin_token: Option<String>,
+) -> std::thread::JoinHandle<()> {
     std::thread::spawn(move || {
+        let whep_sessions = whep::Sessions::default();
+
         rouille::start_server("0.0.0.0:80", move |request| {
             router!(request,
                 // WebRTC Signalling API
@@ -42,11 +85,106 @@ pub fn init(c: Arc<AppController>, rt: Handle) -> std::thread::JoinHandle<()> {
                     rt.block_on(async {
                         match signal(&request, &c).await {
                             Ok(r) => r,
-                            Err(e) => Response::text(e.to_string()).with_status_code(500),
+                            Err(e) => error_response(e),
+                        }
+                     })
+                },
+
+                // Trickle ICE: remote candidates from the browser
+                (POST) (/api/ice) => {
+                    rt.block_on(async {
+                        match add_ice_candidate(request, &c).await {
+                            Ok(r) => r,
+                            Err(e) => error_response(e),
                         }
                      })
                 },
 
+                // Trickle ICE: websocket streaming our local candidates.
+                // A null candidate marks the end of gathering, and closes the socket.
+                (GET) (/api/ice) => {
+                    let uid = match request.get_param("uid") {
+                        Some(uid) => uid,
+                        None => return Response::text("Missing uid").with_status_code(400),
+                    };
+
+                    match rt.block_on(c.subscribe_ice_candidates(&uid)) {
+                        Ok((gathered, rx)) => {
+                            event_socket(request, gathered, rx, rt.clone(), Option::is_none)
+                        }
+                        Err(e) => Response::text(e.to_string()).with_status_code(404),
+                    }
+                },
+
+                // Every client's connection details and streams.
+                // Operator only, as they include client uids and addresses.
+                (GET) (/api/clients) => {
+                    if let Some(r) = unauthorized(request, &admin_token) {
+                        return r;
+                    }
+                    Response::json(&rt.block_on(c.client_infos()))
+                },
+
+                (GET) (/api/clients/{uid: String}) => {
+                    if let Some(r) = unauthorized(request, &admin_token) {
+                        return r;
+                    }
+                    match rt.block_on(c.client_info(&uid)) {
+                        Ok(info) => Response::json(&info),
+                        Err(e) => Response::text(e.to_string()).with_status_code(404),
+                    }
+                },
+
+                // Operator endpoints, for managing other clients.
+                (POST) (/api/admin/kick) => {
+                    if let Some(r) = unauthorized(request, &admin_token) {
+                        return r;
+                    }
+                    rt.block_on(async {
+                        match admin_kick(request, &c).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                (POST) (/api/admin/resync) => {
+                    if let Some(r) = unauthorized(request, &admin_token) {
+                        return r;
+                    }
+                    rt.block_on(async {
+                        match admin_streams(request, &c, false).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                (POST) (/api/admin/streams) => {
+                    if let Some(r) = unauthorized(request, &admin_token) {
+                        return r;
+                    }
+                    rt.block_on(async {
+                        match admin_streams(request, &c, true).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                // Which stream each of a client's transceivers (by mid) is sending.
+                (GET) (/api/tracks) => {
+                    let uid = match request.get_param("uid") {
+                        Some(uid) => uid,
+                        None => return Response::text("Missing uid").with_status_code(400),
+                    };
+
+                    match rt.block_on(c.client_track_mids(&uid)) {
+                        Ok(mids) => Response::json(&mids),
+                        Err(e) => Response::text(e.to_string()).with_status_code(404),
+                    }
+                },
+
                 //
                 (POST) (/api/resync) => {
                     rt.block_on(async {
@@ -57,6 +195,50 @@ pub fn init(c: Arc<AppController>, rt: Handle) -> std::thread::JoinHandle<()> {
                      })
                 },
 
+                // Picks the rendition of a stream's video a client is sent.
+                (POST) (/api/rendition) => {
+                    rt.block_on(async {
+                        match set_rendition(request, &c).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                // Pauses/resumes sending a client's streams (ie while its tab is hidden).
+                (POST) (/api/pause) => {
+                    rt.block_on(async {
+                        match set_paused(request, &c, true).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                (POST) (/api/resume) => {
+                    rt.block_on(async {
+                        match set_paused(request, &c, false).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                // Switches a client's streams between thumbnail mode (keyframes only) and full rate.
+                (POST) (/api/thumbnail) => {
+                    rt.block_on(async {
+                        match set_thumbnail(request, &c).await {
+                            Ok(r) => r,
+                            Err(e) => Response::text(e.to_string()).with_status_code(400),
+                        }
+                    })
+                },
+
+                // ICE servers the browser should use. Fetched before creating a peer connection.
+                (GET) (/api/ice_servers) => {
+                    Response::json(&c.ice_servers())
+                },
+
                 // Pollable endpoint with stats about system
                 (GET) (/api/stats) => {
                     rt.block_on(async { stats(&c).await })
@@ -64,7 +246,51 @@ pub fn init(c: Arc<AppController>, rt: Handle) -> std::thread::JoinHandle<()> {
 
                 // Pollable endpoint with info about all available streams.
                 (GET) (/api/streams) => {
-                    Response::json(&c.streams())
+                    rt.block_on(async { Response::json(&c.streams().await) })
+                },
+
+                // Websocket streaming stream state changes (ie a camera going offline)
+                (GET) (/api/events) => {
+                    let rx = c.subscribe_stream_events();
+                    event_socket(request, Vec::new(), rx, rt.clone(), |_| false)
+                },
+
+                // WHEP endpoint. Lets standard WHEP players view a stream.
+                (POST) (/whep/{stream_id: String}) => {
+                    rt.block_on(async {
+                        match whep::offer(request, &c, &whep_sessions, stream_id).await {
+                            Ok(r) => whep::cors(r),
+                            Err(e) => whep::cors(error_response(e)),
+                        }
+                    })
+                },
+
+                // WHEP session resource: trickle ICE
+                (PATCH) (/whep/{stream_id: String}/{uid: String}) => {
+                    rt.block_on(async {
+                        match whep::patch(request, &c, &whep_sessions, stream_id, uid).await {
+                            Ok(r) => whep::cors(r),
+                            Err(e) => whep::cors(Response::text(e.to_string()).with_status_code(500)),
+                        }
+                    })
+                },
+
+                // WHEP session resource: teardown
+                (DELETE) (/whep/{stream_id: String}/{uid: String}) => {
+                    rt.block_on(async {
+                        match whep::delete(&c, &whep_sessions, stream_id, uid).await {
+                            Ok(r) => whep::cors(r),
+                            Err(e) => whep::cors(Response::text(e.to_string()).with_status_code(500)),
+                        }
+                    })
+                },
+
+                // CORS preflight for browser based WHEP players
+                (OPTIONS) (/whep/{_stream_id: String}) => {
+                    whep::cors(Response::empty_204().with_additional_header("Accept-Post", "application/sdp"))
+                },
+                (OPTIONS) (/whep/{_stream_id: String}/{_uid: String}) => {
+                    whep::cors(Response::empty_204())
                 },
 
                 // default route
@@ -79,6 +305,19 @@ fn serve_default(request: &Request) -> Response {
         .unwrap_or_else(|e| Response::text(e.to_string()).with_status_code(400))
 }
 
+/**
+ * Responds to a failed request. Requests that would exceed the client limit
+ * get a 503, so callers can tell the server is full rather than broken.
+ */
+fn error_response(e: anyhow::Error) -> Response {
+    match e.downcast_ref::<ClientLimitReached>() {
+        Some(limit) => Response::text(limit.to_string())
+            .with_status_code(503)
+            .with_additional_header("Retry-After", "10"),
+        None => Response::text(e.to_string()).with_status_code(500),
+    }
+}
+
 async fn signal(request: &Request, app_controller: &Arc<AppController>) -> Result<Response> {
     println!("Got signalling request");
 
@@ -97,12 +336,18 @@ async fn signal(request: &Request, app_controller: &Arc<AppController>) -> Resul
         .client_sync_streams(&signalling.uid, signalling.stream_ids)
         .await?;
 
+    app_controller
+        .set_client_token(&signalling.uid, signalling.token)
+        .await?;
+
     let signal_result = app_controller
-        .signal(&signalling.uid, signalling.offer)
+        .signal(&signalling.uid, signalling.offer, signalling.trickle)
         .await;
 
     match signal_result {
-        Ok(res) => Ok(Response::json(&res)),
+        Ok(Some(res)) => Ok(Response::json(&res)),
+        // Our own offer is pending on the signalling channel. The client should answer that instead.
+        Ok(None) => Ok(Response::text("Offer collided with a pending server offer").with_status_code(409)),
         Err(e) => {
             eprintln!("Signalling request failed: {}", e);
             Err(anyhow!(e.to_string()))
@@ -110,6 +355,24 @@ async fn signal(request: &Request, app_controller: &Arc<AppController>) -> Resul
     }
 }
 
+async fn add_ice_candidate(
+    request: &Request,
+    app_controller: &Arc<AppController>,
+) -> Result<Response> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+
+    let req: IceCandidateRequest = serde_json::from_str(&buf)?;
+
+    app_controller
+        .add_ice_candidate(&req.uid, req.candidate)
+        .await
+        .map(|_| Response::text("OK").with_status_code(200))
+}
+
 async fn resync(request: &Request, app_controller: &Arc<AppController>) -> Result<Response> {
     // Parse incoming request into a SignallingRequest object
     let mut buf = String::new();
@@ -126,11 +389,185 @@ async fn resync(request: &Request, app_controller: &Arc<AppController>) -> Resul
         .map(|_| Response::text("OK").with_status_code(200))
 }
 
+/**
+ * Compares a token in constant time, so it can't be guessed from response timings.
+ */
+pub fn token_matches(given: &str, expected: &str) -> bool {
+    verify_slices_are_equal(given.as_bytes(), expected.as_bytes()).is_ok()
+}
+
+/**
+ * A 401 response for admin requests without the configured admin token.
+ * Without one configured, the admin endpoints are disabled (403).
+ */
+fn unauthorized(request: &Request, admin_token: &Option<String>) -> Option<Response> {
+    let token = match admin_token {
+        Some(token) => token,
+        None => return Some(Response::text("No admin token configured").with_status_code(403)),
+    };
+
+    let authorized = request
+        .header("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|t| token_matches(t, token));
+
+    match authorized {
+        true => None,
+        false => Some(Response::text("Unauthorized").with_status_code(401)),
+    }
+}
+
+async fn admin_kick(request: &Request, app_controller: &Arc<AppController>) -> Result<Response> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+
+    let req: KickRequest = serde_json::from_str(&buf)?;
+
+    app_controller
+        .kick_client(&req.uid)
+        .await
+        .map(|_| Response::text("OK").with_status_code(200))
+}
+
+/**
+ * Resyncs (or, with assign set, sets) the streams of another client.
+ */
+async fn admin_streams(
+    request: &Request,
+    app_controller: &Arc<AppController>,
+    assign: bool,
+) -> Result<Response> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+
+    let req: SyncRequest = serde_json::from_str(&buf)?;
+
+    let res = match assign {
+        true => app_controller.assign_streams(&req.uid, req.stream_ids).await,
+        false => app_controller.admin_resync_streams(&req.uid, req.stream_ids).await,
+    };
+    res.map(|_| Response::text("OK").with_status_code(200))
+}
+
+async fn set_paused(
+    request: &Request,
+    app_controller: &Arc<AppController>,
+    paused: bool,
+) -> Result<Response> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+
+    let req: SyncRequest = serde_json::from_str(&buf)?;
+
+    app_controller
+        .client_set_paused(&req.uid, &req.stream_ids, paused)
+        .await
+        .map(|_| Response::text("OK").with_status_code(200))
+}
+
+async fn set_rendition(request: &Request, app_controller: &Arc<AppController>) -> Result<Response> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+
+    let req: RenditionRequest = serde_json::from_str(&buf)?;
+
+    app_controller
+        .client_set_rendition(&req.uid, &req.stream_id, req.rendition)
+        .await
+        .map(|_| Response::text("OK").with_status_code(200))
+}
+
+async fn set_thumbnail(request: &Request, app_controller: &Arc<AppController>) -> Result<Response> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+
+    let req: ThumbnailRequest = serde_json::from_str(&buf)?;
+    let interval = match req.interval_s {
+        Some(s) => Some(Duration::try_from_secs_f64(s)?),
+        None => None,
+    };
+
+    app_controller
+        .client_set_thumbnail(&req.uid, &req.stream_ids, interval)
+        .await
+        .map(|_| Response::text("OK").with_status_code(200))
+}
+
 async fn stats(a: &Arc<AppController>) -> Response {
     let stats = a.stats().await;
     Response::json(&stats)
 }
 
+// Interval between keep-alive (empty) messages on idle event sockets.
+// Also bounds how long a closed connection holds onto a thread.
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/**
+ * Upgrades the request to a websocket, and sends each event from the broadcast
+ * receiver over it as a JSON text message. Events in the backlog are sent first.
+ * The socket is closed after the first event `last` returns true for.
+ * Each message is flushed as it's sent, unlike a chunked HTTP response body.
+ */
+fn event_socket<T: Serialize + Clone + Send + 'static>(
+    request: &Request,
+    backlog: Vec<T>,
+    mut rx: broadcast::Receiver<T>,
+    rt: Handle,
+    last: fn(&T) -> bool,
+) -> Response {
+    let (response, socket) = match websocket::start(request, None::<&str>) {
+        Ok(r) => r,
+        Err(e) => return Response::text(e.to_string()).with_status_code(400),
+    };
+
+    std::thread::spawn(move || {
+        // Arrives once the upgrade response has been sent.
+        let mut socket = match socket.recv() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let mut backlog = backlog.into_iter();
+
+        loop {
+            let next = match backlog.next() {
+                Some(ev) => Ok(Ok(ev)),
+                None => rt.block_on(tokio::time::timeout(EVENT_KEEPALIVE, rx.recv())),
+            };
+
+            let (msg, done) = match next {
+                Ok(Ok(ev)) => match serde_json::to_string(&ev) {
+                    Ok(msg) => (msg, last(&ev)),
+                    Err(_) => continue,
+                },
+                Ok(Err(RecvError::Lagged(_))) => continue,
+                Ok(Err(RecvError::Closed)) => break,
+                Err(_) => (String::new(), false),
+            };
+
+            if socket.send_text(&msg).is_err() || done {
+                break;
+            }
+        }
+    });
+
+    response
+}
+
 pub fn match_embedded_asset(request: &Request, root: &str) -> Result<Response> {
     let raw_path = if request.url().len() <= 1 {
         root.to_string()
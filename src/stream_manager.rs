@@ -2,22 +2,69 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, Weak};
 
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::data_track::DataTrack;
+use crate::ingest_health::{TrackState, TrackStateEvent, TrackStatus};
+use crate::ingest_socket::IngestSockets;
+use crate::reorder_buffer::ReorderStats;
 use crate::rtp_track::RtpTrack;
-use crate::StreamDef;
+use crate::{StreamDef, TrackDef};
+
+// Rendition id of a stream's video track itself, the best of its renditions.
+pub const MAIN_RENDITION: &str = "main";
 
 pub struct Stream {
     pub video: Option<Arc<RtpTrack>>,
     pub audio: Option<Arc<RtpTrack>>,
+    pub data: Option<Arc<DataTrack>>,
+    pub renditions: Vec<(String, Arc<RtpTrack>)>, // Best first, starting with the video track
     pub def: StreamDef,
 }
+
+impl Stream {
+    /**
+     * The video track of the passed rendition.
+     */
+    pub fn rendition(&self, id: &str) -> Option<&Arc<RtpTrack>> {
+        self.renditions
+            .iter()
+            .find(|(rendition, _)| rendition == id)
+            .map(|(_, track)| track)
+    }
+}
+
+/**
+ * A stream definition along with the live status of its tracks.
+ * Served by /api/streams.
+ */
+#[derive(Serialize)]
+pub struct StreamInfo {
+    #[serde(flatten)]
+    def: StreamDef,
+    state: TrackState,
+    video_status: Option<TrackStatus>,
+    audio_status: Option<TrackStatus>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    rendition_status: HashMap<String, TrackStatus>, // Lower renditions of the video, by id
+}
+
 pub struct StreamManager {
     streams: HashMap<String, Arc<Stream>>,
+    sockets: IngestSockets,
+    events: Sender<TrackStateEvent>,
 }
 
 impl StreamManager {
     pub fn new() -> StreamManager {
+        let (events, _) = broadcast::channel(100);
+
         StreamManager {
             streams: HashMap::new(),
+            sockets: IngestSockets::new(),
+            events,
         }
     }
 
@@ -35,7 +82,9 @@ impl StreamManager {
 
         // Instantiate new streams
         for stream in created_streams {
-            self.create_stream(stream.clone());
+            if let Err(e) = self.create_stream(stream.clone()) {
+                eprintln!("Failed to create stream {}: {}", stream.id, e);
+            }
         }
 
         let deleted_streams = current_streams.difference(&incoming_streams);
@@ -44,30 +93,82 @@ impl StreamManager {
         for _stream in deleted_streams {}
     }
 
-    pub fn create_stream(&mut self, def: StreamDef) -> Arc<Stream> {
+    pub fn create_stream(&mut self, def: StreamDef) -> Result<Arc<Stream>> {
         let id = def.id.to_string();
         if self.streams.contains_key(&id) {
-            panic!("Already contains stream");
+            bail!("Stream {} already exists", id);
+        }
+
+        let video = match def.video {
+            Some(ref t) => Some(self.create_track(t, &def, t.stream_id())?),
+            None => None,
+        };
+        let audio = match def.audio {
+            Some(ref t) => Some(self.create_track(t, &def, t.stream_id())?),
+            None => None,
+        };
+
+        // Renditions are switched between on the same client track, so they must share its codec.
+        let mut renditions: Vec<(String, Arc<RtpTrack>)> = video
+            .iter()
+            .map(|t| (MAIN_RENDITION.to_string(), t.clone()))
+            .collect();
+        for r in def.renditions.iter() {
+            let main = def
+                .video
+                .as_ref()
+                .ok_or(anyhow!("Renditions of {} need a video track", id))?;
+            if r.track.mime_type()? != main.mime_type()? {
+                bail!("Rendition {} of {} must use the video's codec", r.id, id);
+            }
+            if renditions.iter().any(|(existing, _)| *existing == r.id) {
+                bail!("Duplicate rendition {} in {}", r.id, id);
+            }
+            let name = format!("{}/{}", main.stream_id(), r.id);
+            renditions.push((r.id.clone(), self.create_track(&r.track, &def, &name)?));
         }
 
-        let video = def
-            .video
-            .as_ref()
-            .map(|t| Arc::new(RtpTrack::new(&t, &def)));
-        let audio = def
-            .audio
-            .as_ref()
-            .map(|t| Arc::new(RtpTrack::new(&t, &def)));
+        let data = match def.data {
+            Some(ref d) => Some(Arc::new(DataTrack::new(d)?)),
+            None => None,
+        };
 
         let s = Arc::new(Stream {
             video,
             audio,
+            data,
+            renditions,
             def: def.clone(),
         });
 
         self.streams.insert(def.id.clone(), s.clone());
 
-        s
+        Ok(s)
+    }
+
+    /**
+     * Creates an RtpTrack, sharing ingest sockets with any existing
+     * tracks listening on the same addresses.
+     */
+    fn create_track(
+        &self,
+        track_def: &TrackDef,
+        def: &StreamDef,
+        name: &str,
+    ) -> Result<Arc<RtpTrack>> {
+        let mut inputs = Vec::new();
+        for input in track_def.inputs() {
+            let socket = self.sockets.get_or_bind(&input.ingest_addr())?;
+            inputs.push((input, socket));
+        }
+
+        Ok(Arc::new(RtpTrack::new(
+            track_def,
+            def,
+            name.to_string(),
+            inputs,
+            self.events.clone(),
+        )))
     }
 
     pub fn delete_stream(&mut self, id: &String) {
@@ -76,13 +177,73 @@ impl StreamManager {
     }
 
     pub fn get_stream(&self, stream_id: &String) -> Option<Arc<Stream>> {
-        self.streams
-            .get(stream_id)
-            .clone()
-            .map(|f| f.clone())
+        self.streams.get(stream_id).clone().map(|f| f.clone())
+    }
+
+    /**
+     * Stream definitions along with the current status of each stream's tracks.
+     * A stream's overall state is that of its video track, if it has one.
+     */
+    pub async fn stream_infos(&self) -> Vec<StreamInfo> {
+        let mut infos = Vec::new();
+        for s in self.streams.values() {
+            let video_status = match s.video {
+                Some(ref t) => Some(t.status().await),
+                None => None,
+            };
+            let audio_status = match s.audio {
+                Some(ref t) => Some(t.status().await),
+                None => None,
+            };
+
+            let mut rendition_status = HashMap::new();
+            for (id, t) in s.renditions.iter().skip(1) {
+                rendition_status.insert(id.clone(), t.status().await);
+            }
+
+            let state = video_status
+                .as_ref()
+                .or(audio_status.as_ref())
+                .map_or(TrackState::Waiting, |s| s.state());
+
+            infos.push(StreamInfo {
+                def: s.def.clone(),
+                state,
+                video_status,
+                audio_status,
+                rendition_status,
+            });
+        }
+        infos
     }
 
-    pub fn stream_defs(&self) -> Vec<StreamDef> {
-        self.streams.values().map(|f| f.def.clone()).collect()
+    /**
+     * Returns a receiver of track state changes (ie a stream going live or stalling)
+     */
+    pub fn subscribe_events(&self) -> Receiver<TrackStateEvent> {
+        self.events.subscribe()
+    }
+
+    /**
+     * Reorder buffer counters for every track, keyed by `<stream id>/<video|audio>`,
+     * or `<stream id>/video/<rendition id>` for lower renditions of the video.
+     */
+    pub async fn reorder_stats(&self) -> HashMap<String, ReorderStats> {
+        let mut stats = HashMap::new();
+        for s in self.streams.values() {
+            if let Some(ref t) = s.video {
+                stats.insert(format!("{}/video", s.def.id), t.reorder_stats().await);
+            }
+            if let Some(ref t) = s.audio {
+                stats.insert(format!("{}/audio", s.def.id), t.reorder_stats().await);
+            }
+            for (id, t) in s.renditions.iter().skip(1) {
+                stats.insert(
+                    format!("{}/video/{}", s.def.id, id),
+                    t.reorder_stats().await,
+                );
+            }
+        }
+        stats
     }
 }
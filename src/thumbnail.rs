@@ -0,0 +1,162 @@
+// Thumbnail mode: sending a client only a stream's keyframes, for grid views of many streams.
+// Keyframes decode on their own, so everything in between can be skipped.
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use webrtc::rtp::packet::Packet;
+
+use crate::TrackDef;
+
+/**
+ * Picks the frames sent to a client in thumbnail mode: whole keyframes, at most one per interval.
+ * Packets are held until their frame's last packet (marked by the RTP marker bit),
+ * since a frame's first packets (ie H264 parameter sets) don't say whether it's a keyframe.
+ */
+pub struct KeyframeSampler {
+    interval: Duration, // Minimum time between sent keyframes. Zero sends every one
+    frame: Vec<Arc<Packet>>,
+    is_keyframe: bool,
+    last_sent: Option<Instant>,
+}
+
+impl KeyframeSampler {
+    pub fn new(interval: Duration) -> KeyframeSampler {
+        KeyframeSampler {
+            interval,
+            frame: Vec::new(),
+            is_keyframe: false,
+            last_sent: None,
+        }
+    }
+
+    /**
+     * Takes the next packet of the track. Returns the frame it completes, if that frame is sent.
+     */
+    pub fn push(
+        &mut self,
+        pkt: Arc<Packet>,
+        def: &TrackDef,
+        now: Instant,
+    ) -> Option<Vec<Arc<Packet>>> {
+        // A new timestamp before the last frame's marker means its end was lost.
+        if self
+            .frame
+            .last()
+            .is_some_and(|p| p.header.timestamp != pkt.header.timestamp)
+        {
+            self.frame.clear();
+            self.is_keyframe = false;
+        }
+
+        self.is_keyframe |= def.is_keyframe(&pkt);
+        let marker = pkt.header.marker;
+        self.frame.push(pkt);
+
+        if !marker {
+            return None;
+        }
+
+        let frame = std::mem::take(&mut self.frame);
+        let is_keyframe = std::mem::replace(&mut self.is_keyframe, false);
+        let due = self
+            .last_sent
+            .is_none_or(|at| now.duration_since(at) >= self.interval);
+
+        if !(is_keyframe && due) {
+            return None;
+        }
+        self.last_sent = Some(now);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use webrtc::rtp::header::Header;
+
+    const SPS: u8 = 0x67;
+    const IDR: u8 = 0x65;
+    const NON_IDR: u8 = 0x41;
+
+    fn def() -> TrackDef {
+        TrackDef {
+            codec: "h264".to_string(),
+            ..Default::default()
+        }
+    }
+
+    // An H264 NAL unit as a single packet. The marker ends the frame.
+    fn pkt(seq: u16, ts: u32, nal: u8, marker: bool) -> Arc<Packet> {
+        Arc::new(Packet {
+            header: Header {
+                sequence_number: seq,
+                timestamp: ts,
+                marker,
+                ..Default::default()
+            },
+            payload: Bytes::from(vec![nal, 0x88]),
+        })
+    }
+
+    fn seqs(frame: Option<Vec<Arc<Packet>>>) -> Option<Vec<u16>> {
+        frame.map(|f| f.iter().map(|p| p.header.sequence_number).collect())
+    }
+
+    #[test]
+    fn sends_whole_keyframes() {
+        let mut s = KeyframeSampler::new(Duration::ZERO);
+        let now = Instant::now();
+
+        assert!(s.push(pkt(1, 1000, SPS, false), &def(), now).is_none());
+        assert!(s.push(pkt(2, 1000, IDR, false), &def(), now).is_none());
+        assert_eq!(
+            seqs(s.push(pkt(3, 1000, IDR, true), &def(), now)),
+            Some(vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn skips_other_frames() {
+        let mut s = KeyframeSampler::new(Duration::ZERO);
+        let now = Instant::now();
+
+        assert!(s.push(pkt(1, 1000, NON_IDR, false), &def(), now).is_none());
+        assert!(s.push(pkt(2, 1000, NON_IDR, true), &def(), now).is_none());
+        assert_eq!(
+            seqs(s.push(pkt(3, 4000, IDR, true), &def(), now)),
+            Some(vec![3])
+        );
+    }
+
+    #[test]
+    fn sends_at_most_one_keyframe_per_interval() {
+        let mut s = KeyframeSampler::new(Duration::from_secs(1));
+        let start = Instant::now();
+
+        assert!(s.push(pkt(1, 0, IDR, true), &def(), start).is_some());
+        let early = start + Duration::from_millis(500);
+        assert!(s.push(pkt(2, 45000, IDR, true), &def(), early).is_none());
+        let due = start + Duration::from_secs(1);
+        assert!(s.push(pkt(3, 90000, IDR, true), &def(), due).is_some());
+    }
+
+    #[test]
+    fn drops_frames_missing_their_end() {
+        let mut s = KeyframeSampler::new(Duration::ZERO);
+        let now = Instant::now();
+
+        // The keyframe's marker packet was lost. The next frame isn't a keyframe.
+        assert!(s.push(pkt(1, 1000, IDR, false), &def(), now).is_none());
+        assert!(s.push(pkt(3, 4000, NON_IDR, true), &def(), now).is_none());
+
+        // Nor are a truncated frame's packets sent along with the keyframe after it.
+        assert!(s.push(pkt(4, 7000, NON_IDR, false), &def(), now).is_none());
+        assert_eq!(
+            seqs(s.push(pkt(6, 10000, IDR, true), &def(), now)),
+            Some(vec![6])
+        );
+    }
+}
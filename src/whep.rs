@@ -0,0 +1,269 @@
+// WHEP (WebRTC-HTTP Egress Protocol) endpoint.
+// https://datatracker.ietf.org/doc/draft-murillo-whep/
+// Lets off-the-shelf players (ie GStreamer's whepsrc) view a stream without
+// speaking our own signalling API. Every WHEP session is a regular client.
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Result};
+use rouille::{Request, Response};
+use webrtc::{
+    ice_transport::ice_candidate::RTCIceCandidateInit,
+    peer_connection::sdp::session_description::RTCSessionDescription,
+};
+
+use crate::app_controller::AppController;
+
+const SDP_MIME: &str = "application/sdp";
+const SDPFRAG_MIME: &str = "application/trickle-ice-sdpfrag";
+
+/**
+ * Live WHEP sessions, by uid, and the stream each was created for.
+ * A session's resource URL is only valid under its own stream, and only WHEP
+ * sessions can be managed through it.
+ */
+#[derive(Default)]
+pub struct Sessions {
+    streams: Mutex<HashMap<String, String>>,
+}
+
+impl Sessions {
+    fn is_session(&self, uid: &str, stream_id: &str) -> bool {
+        self.streams
+            .lock()
+            .unwrap()
+            .get(uid)
+            .is_some_and(|s| s == stream_id)
+    }
+
+    /**
+     * Forgets sessions whose clients are gone without being deleted (ie they timed out).
+     */
+    async fn prune(&self, app_controller: &AppController) {
+        let uids: Vec<String> = self.streams.lock().unwrap().keys().cloned().collect();
+        for uid in uids {
+            if !app_controller.has_client(&uid).await {
+                self.streams.lock().unwrap().remove(&uid);
+            }
+        }
+    }
+}
+
+fn read_body(request: &Request) -> Result<String> {
+    let mut buf = String::new();
+    request
+        .data()
+        .ok_or(anyhow!("No request data received"))?
+        .read_to_string(&mut buf)?;
+    Ok(buf)
+}
+
+fn has_content_type(request: &Request, mime: &str) -> bool {
+    request
+        .header("Content-Type")
+        .is_some_and(|c| c.split(';').next().unwrap_or("").trim() == mime)
+}
+
+/**
+ * Adds the CORS headers browser based WHEP players need.
+ */
+pub fn cors(response: Response) -> Response {
+    response
+        .with_additional_header("Access-Control-Allow-Origin", "*")
+        .with_additional_header(
+            "Access-Control-Allow-Methods",
+            "POST, PATCH, DELETE, OPTIONS",
+        )
+        .with_additional_header("Access-Control-Allow-Headers", "Content-Type, If-Match")
+        .with_additional_header("Access-Control-Expose-Headers", "Location")
+}
+
+/**
+ * Creates a session viewing the passed stream from an SDP offer.
+ * Responds with the SDP answer and the session's resource URL.
+ */
+pub async fn offer(
+    request: &Request,
+    app_controller: &Arc<AppController>,
+    sessions: &Sessions,
+    stream_id: String,
+) -> Result<Response> {
+    if !has_content_type(request, SDP_MIME) {
+        return Ok(Response::text("Expected an SDP offer").with_status_code(415));
+    }
+
+    if !app_controller.has_stream(&stream_id) {
+        return Ok(Response::text("Unknown stream").with_status_code(404));
+    }
+
+    let offer = RTCSessionDescription::offer(read_body(request)?)?;
+
+    let uid = format!("whep-{:016x}", rand::random::<u64>());
+    println!("WHEP: new session {} for stream {}", uid, stream_id);
+
+    app_controller
+        .client_sync_streams(&uid, vec![stream_id.clone()])
+        .await?;
+
+    // Not every WHEP player trickles, so the answer includes all of our candidates.
+    // WHEP sessions have no signalling channel, so there's never a pending offer to collide with.
+    let answer = app_controller
+        .signal(&uid, offer, false)
+        .await?
+        .ok_or(anyhow!("Signalling collision"))?;
+
+    sessions.prune(app_controller).await;
+    sessions
+        .streams
+        .lock()
+        .unwrap()
+        .insert(uid.clone(), stream_id.clone());
+
+    Ok(Response::from_data(SDP_MIME, answer.sdp)
+        .with_status_code(201)
+        .with_additional_header("Location", format!("/whep/{}/{}", stream_id, uid)))
+}
+
+/**
+ * Applies remote ICE candidates trickled in as an SDP fragment.
+ */
+pub async fn patch(
+    request: &Request,
+    app_controller: &Arc<AppController>,
+    sessions: &Sessions,
+    stream_id: String,
+    uid: String,
+) -> Result<Response> {
+    if !has_content_type(request, SDPFRAG_MIME) {
+        return Ok(Response::text("Expected a trickle ICE SDP fragment").with_status_code(415));
+    }
+
+    if !sessions.is_session(&uid, &stream_id) || !app_controller.has_client(&uid).await {
+        return Ok(Response::text("Unknown session").with_status_code(404));
+    }
+
+    for candidate in parse_sdpfrag(&read_body(request)?) {
+        app_controller.add_ice_candidate(&uid, candidate).await?;
+    }
+
+    Ok(Response::empty_204())
+}
+
+/**
+ * Tears the session down.
+ */
+pub async fn delete(
+    app_controller: &Arc<AppController>,
+    sessions: &Sessions,
+    stream_id: String,
+    uid: String,
+) -> Result<Response> {
+    if !sessions.is_session(&uid, &stream_id) {
+        return Ok(Response::text("Unknown session").with_status_code(404));
+    }
+    sessions.streams.lock().unwrap().remove(&uid);
+
+    match app_controller.discard_client(&uid).await {
+        Ok(()) => Ok(Response::text("OK")),
+        Err(e) => Ok(Response::text(e.to_string()).with_status_code(404)),
+    }
+}
+
+/**
+ * Extracts the ICE candidates from a trickle-ice-sdpfrag body (RFC 8840).
+ * Candidates are associated with the media section (m= line) they're listed under.
+ */
+fn parse_sdpfrag(frag: &str) -> Vec<RTCIceCandidateInit> {
+    let mut candidates = Vec::new();
+    let mut ufrag = None;
+    let mut mline_index: Option<u16> = None;
+    let mut mid = None;
+
+    for line in frag.lines().map(str::trim) {
+        if let Some(u) = line.strip_prefix("a=ice-ufrag:") {
+            ufrag = Some(u.to_string());
+        } else if line.starts_with("m=") {
+            mline_index = Some(mline_index.map_or(0, |i| i + 1));
+            mid = None;
+        } else if let Some(m) = line.strip_prefix("a=mid:") {
+            mid = Some(m.to_string());
+        } else if let Some(c) = line.strip_prefix("a=") {
+            if c.starts_with("candidate:") {
+                candidates.push(RTCIceCandidateInit {
+                    candidate: c.to_string(),
+                    sdp_mid: mid.clone(),
+                    sdp_mline_index: mline_index,
+                    username_fragment: ufrag.clone(),
+                });
+            }
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_candidates_per_media_section() {
+        let frag = "a=ice-ufrag:EsAw\r\n\
+                    a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+                    m=audio 9 RTP/AVP 0\r\n\
+                    a=mid:0\r\n\
+                    a=candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host\r\n\
+                    a=candidate:3471623853 1 udp 2122194687 198.51.100.2 61765 typ host\r\n\
+                    m=video 9 RTP/AVP 96\r\n\
+                    a=mid:1\r\n\
+                    a=candidate:2 1 udp 1686052607 203.0.113.3 61666 typ srflx\r\n\
+                    a=end-of-candidates\r\n";
+
+        let candidates = parse_sdpfrag(frag);
+        assert_eq!(candidates.len(), 3);
+
+        assert_eq!(
+            candidates[0].candidate,
+            "candidate:1387637174 1 udp 2122260223 192.0.2.1 61764 typ host"
+        );
+        assert_eq!(candidates[0].sdp_mid.as_deref(), Some("0"));
+        assert_eq!(candidates[0].sdp_mline_index, Some(0));
+        assert_eq!(candidates[0].username_fragment.as_deref(), Some("EsAw"));
+
+        assert_eq!(candidates[1].sdp_mline_index, Some(0));
+        assert_eq!(candidates[2].sdp_mid.as_deref(), Some("1"));
+        assert_eq!(candidates[2].sdp_mline_index, Some(1));
+    }
+
+    #[test]
+    fn candidate_without_media_section() {
+        let candidates = parse_sdpfrag("a=candidate:1 1 udp 1 192.0.2.1 5000 typ host\n");
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].sdp_mid, None);
+        assert_eq!(candidates[0].sdp_mline_index, None);
+        assert_eq!(candidates[0].username_fragment, None);
+    }
+
+    #[test]
+    fn ignores_malformed_input() {
+        assert!(parse_sdpfrag("").is_empty());
+        assert!(parse_sdpfrag("\r\n\r\n").is_empty());
+        assert!(parse_sdpfrag("not an sdp fragment").is_empty());
+        assert!(parse_sdpfrag("candidate:1 1 udp 1 192.0.2.1 5000 typ host").is_empty());
+        assert!(parse_sdpfrag("a=end-of-candidates\na=ice-ufrag:x\nm=").is_empty());
+        assert!(parse_sdpfrag("a=\na=mid:\na=candidate").is_empty());
+    }
+
+    #[test]
+    fn mid_resets_on_new_media_section() {
+        let frag = "m=audio 9 RTP/AVP 0\na=mid:0\nm=video 9 RTP/AVP 96\n\
+                    a=candidate:1 1 udp 1 192.0.2.1 5000 typ host\n";
+        let candidates = parse_sdpfrag(frag);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].sdp_mid, None);
+        assert_eq!(candidates[0].sdp_mline_index, Some(1));
+    }
+}